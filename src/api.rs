@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+
+use serde_json::Value;
+use ureq::Proxy;
+
+use crate::packet::http::HttpAPIResponse;
+use crate::wbi::default_cache_dir;
+
+pub const DEFAULT_LIVE_API_BASE_URL: &str = "https://api.live.bilibili.com";
+pub const DEFAULT_API_BASE_URL: &str = "https://api.bilibili.com";
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/58.0.3029.110 Safari/537.3";
+
+// Delay before the first retry, growing linearly with attempts
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Settings shared by every HTTP API request
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    /// Base URL of live APIs, e.g. room data and danmaku history
+    pub live_base_url: String,
+    /// Base URL of main site APIs, e.g. WBI keys and buvid cookies
+    pub base_url: String,
+    pub user_agent: String,
+    /// Timeout of a whole request, from connecting to reading the response body
+    pub timeout: Duration,
+    /// Extra attempts after a request fails with network error or server error
+    pub retries: u32,
+    pub proxy: Option<Proxy>,
+    /// Directory of WBI key cache
+    pub wbi_cache_dir: PathBuf,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            live_base_url: DEFAULT_LIVE_API_BASE_URL.to_string(),
+            base_url: DEFAULT_API_BASE_URL.to_string(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            timeout: Duration::from_secs(10),
+            retries: 2,
+            proxy: None,
+            wbi_cache_dir: default_cache_dir(),
+        }
+    }
+}
+
+/// Client of Bilibili HTTP APIs. Requests are sent through one shared agent so connections are reused,
+/// point the base URLs to a local server to run against stubbed responses.
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    agent: ureq::Agent,
+    config: ApiConfig,
+}
+
+impl ApiClient {
+    pub fn new(config: ApiConfig) -> Self {
+        let mut builder = ureq::Agent::config_builder()
+            .timeout_global(Some(config.timeout))
+            .user_agent(config.user_agent.as_str());
+        // Keep proxy from environment variables unless one is specified
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Some(proxy.clone()));
+        }
+        ApiClient {
+            agent: builder.build().new_agent(),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &ApiConfig {
+        &self.config
+    }
+
+    /// Full URL of a live API path, e.g. `/room/v1/Room/room_init?id=1`
+    pub fn live_url(&self, path: &str) -> String {
+        format!("{}{}", self.config.live_base_url.trim_end_matches('/'), path)
+    }
+
+    /// Full URL of a main site API path, e.g. `/x/web-interface/nav`
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.base_url.trim_end_matches('/'), path)
+    }
+
+    /// Send a GET request and read the response envelope, retrying on network errors and server errors.
+    /// Response code is not checked, it is up to the caller.
+    pub fn get_json(&self, url: &str, headers: &[(&str, &str)]) -> Result<HttpAPIResponse<Value>, ureq::Error> {
+        let mut attempt = 0;
+        loop {
+            match self.send(url, headers) {
+                Err(e) if attempt < self.config.retries && retryable(&e) => {
+                    attempt += 1;
+                    log::debug!(target: "api", "Request to {} failed ({}), retry {}/{}", url, e, attempt, self.config.retries);
+                    sleep(RETRY_DELAY * attempt);
+                }
+                result => return result
+            }
+        }
+    }
+
+    fn send(&self, url: &str, headers: &[(&str, &str)]) -> Result<HttpAPIResponse<Value>, ureq::Error> {
+        let mut request = self.agent.get(url);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.call()?
+            .body_mut()
+            .read_json::<HttpAPIResponse<Value>>()
+    }
+}
+
+// Client errors and malformed responses fail the same way again
+fn retryable(err: &ureq::Error) -> bool {
+    match err {
+        ureq::Error::StatusCode(code) => *code >= 500,
+        ureq::Error::Io(_)
+        | ureq::Error::Timeout(_)
+        | ureq::Error::HostNotFound
+        | ureq::Error::ConnectionFailed
+        | ureq::Error::BodyStalled => true,
+        _ => false
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use chrono::{TimeDelta, Utc};
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::{interval_at, sleep, Instant, Interval};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tungstenite::Message;
+
+use crate::client::{ClientError, FrameReceiver};
+use crate::depack::DepackedMessage;
+use crate::message::LiveMessage;
+use crate::packet::encoder::PacketEncoder;
+use crate::session_data::SessionData;
+use crate::packet::ws::Protover;
+
+/// Async counterpart of `LiveClient`, sharing packet encoding and depacking with it
+pub struct AsyncLiveClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    session: SessionData,
+    receiver: FrameReceiver,
+    encoder: PacketEncoder,
+}
+
+impl AsyncLiveClient {
+    pub async fn connect(host_url: &str, session: SessionData, protover: Protover) -> Result<Self, ClientError> {
+        let (socket, _) = connect_async(host_url).await
+            .map_err(ClientError::TungsteniteError)?;
+        let mut client = AsyncLiveClient {
+            socket,
+            session,
+            receiver: FrameReceiver::new(),
+            encoder: PacketEncoder::new(),
+        };
+        let certificate = client.encoder
+            .certificate(client.session.uid, client.session.room_id, &client.session.token, protover)
+            .map_err(|_| ClientError::PacketProcessError)?;
+        client.send_message(Message::binary(certificate)).await?;
+        log::debug!(target: "client", "Certificate packet sent");
+        Ok(client)
+    }
+
+    pub async fn send_message(&mut self, message: Message) -> Result<(), ClientError> {
+        self.socket.send(message).await.map_err(ClientError::TungsteniteError)
+    }
+
+    pub async fn send_heartbeat(&mut self) -> Result<(), ClientError> {
+        let heartbeat = self.encoder.heartbeat()
+            .map_err(|_| ClientError::PacketProcessError)?;
+        self.send_message(Message::binary(heartbeat)).await
+    }
+
+    /// Wait for next WebSocket message and return depacked messages in it
+    pub async fn recv_messages(&mut self) -> Result<Vec<DepackedMessage>, ClientError> {
+        let msg = match self.socket.next().await {
+            Some(Ok(msg)) => msg,
+            Some(Err(tungstenite::Error::ConnectionClosed)) | None => return Err(ClientError::ConnectionClosed),
+            Some(Err(e)) => return Err(ClientError::TungsteniteError(e))
+        };
+        if msg.is_close() {
+            return Err(ClientError::ConnectionClosed);
+        }
+        self.receiver.receive(&msg)
+    }
+
+    /// Turn the client into a stream of live messages, heartbeats are sent while the stream is polled.
+    /// The stream ends when the server closes the connection, or after yielding an error.
+    /// `ClientError::HeartbeatTimeout` is yielded if no heartbeat response arrives within `heartbeat_timeout`.
+    pub fn into_stream(
+        self,
+        heartbeat_interval: Duration,
+        heartbeat_timeout: Duration
+    ) -> impl Stream<Item = Result<LiveMessage, ClientError>> {
+        let state = StreamState {
+            client: self,
+            // First heartbeat is sent after one interval, the same as the sync reader
+            heartbeat: interval_at(Instant::now() + heartbeat_interval, heartbeat_interval),
+            heartbeat_timeout: TimeDelta::from_std(heartbeat_timeout).unwrap_or(TimeDelta::MAX),
+            pending: VecDeque::new(),
+            finished: false,
+        };
+        futures_util::stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(message) = state.pending.pop_front() {
+                    return Some((Ok(message), state));
+                }
+                if state.finished {
+                    return None;
+                }
+                let deadline = state.client.receiver.watchdog_deadline(state.heartbeat_timeout);
+                let result = tokio::select! {
+                    result = state.client.recv_messages() => result.map(|messages| {
+                        for message in messages {
+                            state.pending.extend(message.into_live_messages());
+                        }
+                    }),
+                    // Like the sync reader, a failed heartbeat is left for the reader or the watchdog to notice
+                    _ = state.heartbeat.tick() => {
+                        if let Err(e) = state.client.send_heartbeat().await {
+                            log::warn!(target: "client", "Failed to send heartbeat packet:\n {}", e);
+                        }
+                        Ok(())
+                    }
+                    // Watchdog for dead connections
+                    _ = sleep((deadline - Utc::now()).to_std().unwrap_or_default()) => {
+                        state.client.receiver.watchdog(state.heartbeat_timeout)
+                    }
+                };
+                match result {
+                    Ok(()) => {}
+                    Err(ClientError::ConnectionClosed) => return None,
+                    Err(e) => {
+                        state.finished = true;
+                        return Some((Err(e), state));
+                    }
+                }
+            }
+        })
+    }
+}
+
+struct StreamState {
+    client: AsyncLiveClient,
+    heartbeat: Interval,
+    heartbeat_timeout: TimeDelta,
+    pending: VecDeque<LiveMessage>,
+    finished: bool,
+}
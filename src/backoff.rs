@@ -0,0 +1,102 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Exponential backoff with jitter for reconnecting
+pub struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(initial_delay: Duration, max_delay: Duration) -> Self {
+        Backoff {
+            initial_delay,
+            max_delay: max_delay.max(initial_delay),
+            failures: 0,
+        }
+    }
+
+    /// Number of consecutive failures since last reset
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Record a failure and return the delay before next attempt.
+    /// The delay doubles every failure up to max delay, then randomized between half and full delay.
+    pub fn next_delay(&mut self) -> Duration {
+        let exponent = self.failures.min(31);
+        self.failures += 1;
+        let delay = self.initial_delay
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        delay.mul_f64(0.5 + random_fraction() * 0.5)
+    }
+}
+
+// Random number in [0, 1), std randomly seeds every RandomState so no extra dependency is needed
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Jittered delay is between half and full of the expected delay
+    fn assert_jittered(delay: Duration, expected: Duration) {
+        assert!(delay >= expected / 2 && delay <= expected, "{:?} not within jitter of {:?}", delay, expected);
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+        for expected_ms in [100, 200, 400, 800, 1000, 1000] {
+            assert_jittered(backoff.next_delay(), Duration::from_millis(expected_ms));
+        }
+        assert_eq!(backoff.failures(), 6);
+    }
+
+    #[test]
+    fn jitter_stays_within_range() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(1));
+        let delays: Vec<Duration> = (0..200).map(|_| backoff.next_delay()).collect();
+        for delay in delays.iter() {
+            assert_jittered(*delay, Duration::from_secs(1));
+        }
+        // Randomized, not the same delay every time
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn many_failures_do_not_overflow() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::MAX);
+        backoff.failures = 100;
+        assert_jittered(backoff.next_delay(), Duration::from_secs(1 << 31));
+
+        // Multiplying overflows the duration itself
+        let max_delay = Duration::from_secs(u64::MAX / 2);
+        let mut backoff = Backoff::new(Duration::from_secs(u64::MAX / 4), max_delay);
+        backoff.failures = 10;
+        assert_jittered(backoff.next_delay(), max_delay);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(10));
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_eq!(backoff.failures(), 0);
+        assert_jittered(backoff.next_delay(), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,339 @@
+/*
+    Capture file format (version 1), all integers are big-endian:
+
+    File header (8 bytes)
+        magic       [u8; 4]     b"BLDC"
+        version     u16         1
+        reserved    u16         0
+
+    Followed by frame records until EOF
+        timestamp   i64         Unix timestamp in milliseconds when the frame was sent / received
+        direction   u8          0 = received from server, 1 = sent to server
+        length      u32         Length of the frame data, at most MAX_PACKET_SIZE
+        data        [u8]        Raw binary WebSocket frame, starting with the outer packet header
+
+    Session token and uid in outbound certificate packets are redacted, see `redact_certificate`
+*/
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use bincode::Options;
+use chrono::Utc;
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+use crate::packet::ws::{CertificatePacketBody, Packet, PacketType, MAX_PACKET_SIZE};
+
+pub const CAPTURE_MAGIC: [u8; 4] = *b"BLDC";
+pub const CAPTURE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameDirection {
+    Inbound     = 0,
+    Outbound    = 1,
+}
+
+impl TryFrom<u8> for FrameDirection {
+    type Error = ();
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FrameDirection::Inbound),
+            1 => Ok(FrameDirection::Outbound),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureFileHeader {
+    pub magic: [u8; 4],
+    pub version: u16,
+    pub reserved: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureRecordHeader {
+    pub timestamp_ms: i64,
+    pub direction: u8,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureFrame {
+    pub timestamp_ms: i64,
+    pub direction: FrameDirection,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Display)]
+pub enum CaptureError {
+    IOError(std::io::Error),
+    BinCodeError(bincode::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    InvalidDirection(u8),
+    FrameTooLarge(u32),
+    TruncatedRecord,
+}
+
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self {
+            Self::IOError(e) => Some(e),
+            Self::BinCodeError(e) => Some(e),
+            Self::InvalidMagic => None,
+            Self::UnsupportedVersion(_) => None,
+            Self::InvalidDirection(_) => None,
+            Self::FrameTooLarge(_) => None,
+            Self::TruncatedRecord => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CaptureError {
+    fn from(err: std::io::Error) -> Self {
+        CaptureError::IOError(err)
+    }
+}
+
+impl From<bincode::Error> for CaptureError {
+    fn from(err: bincode::Error) -> Self {
+        CaptureError::BinCodeError(err)
+    }
+}
+
+pub fn capture_bincode_options() -> impl Options {
+    bincode::options()
+        .with_fixint_encoding()
+        .with_big_endian()
+}
+
+/// Capture file writer, cloned writers append to the same file
+#[derive(Clone)]
+pub struct CaptureWriter {
+    writer: Arc<Mutex<BufWriter<File>>>
+}
+
+impl CaptureWriter {
+    pub fn create(path: &str) -> Result<Self, CaptureError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let header = CaptureFileHeader {
+            magic: CAPTURE_MAGIC,
+            version: CAPTURE_VERSION,
+            reserved: 0,
+        };
+        writer.write_all(&capture_bincode_options().serialize(&header)?)?;
+        writer.flush()?;
+        Ok(CaptureWriter { writer: Arc::new(Mutex::new(writer)) })
+    }
+
+    pub fn write_frame(&self, direction: FrameDirection, data: &[u8]) -> Result<(), CaptureError> {
+        let data = match direction {
+            FrameDirection::Outbound => redact_certificate(data),
+            FrameDirection::Inbound => Cow::Borrowed(data)
+        };
+        let header = CaptureRecordHeader {
+            timestamp_ms: Utc::now().timestamp_millis(),
+            direction: direction as u8,
+            length: data.len() as u32,
+        };
+        let header = capture_bincode_options().serialize(&header)?;
+        // A poisoned lock only means another writer panicked, the file itself is still usable
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(&header)?;
+        writer.write_all(&data)?;
+        // Flush every frame so the capture survives the process being killed
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Clear session token and uid of a certificate packet, capture files are meant to be attached to bug reports.
+/// Other frames, and frames that can not be parsed as a certificate, are returned as they are.
+pub fn redact_certificate(data: &[u8]) -> Cow<'_, [u8]> {
+    let Ok(packet) = Packet::from_binary(data) else {
+        return Cow::Borrowed(data);
+    };
+    if packet.header.packet_type != PacketType::Certificate as u32 {
+        return Cow::Borrowed(data);
+    }
+    let Ok(mut body) = serde_json::from_slice::<CertificatePacketBody>(&packet.body) else {
+        return Cow::Borrowed(data);
+    };
+    body.uid = 0;
+    body.key = String::new();
+    let Ok(body) = serde_json::to_vec(&body) else {
+        return Cow::Borrowed(data);
+    };
+    // Sequence and protocol are kept, only the body is replaced
+    let mut header = packet.header;
+    header.head_size = 16;
+    header.total_size = (body.len() + 16) as u32;
+    match (Packet { header, body }).to_binary() {
+        Ok(redacted) => Cow::Owned(redacted),
+        Err(_) => Cow::Borrowed(data)
+    }
+}
+
+/// Capture file reader, yields frames in the recorded order
+pub struct CaptureReader {
+    reader: BufReader<File>,
+    finished: bool,
+}
+
+impl CaptureReader {
+    pub fn open(path: &str) -> Result<Self, CaptureError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        let header: CaptureFileHeader = capture_bincode_options().deserialize(&header)?;
+        if header.magic != CAPTURE_MAGIC {
+            return Err(CaptureError::InvalidMagic);
+        }
+        if header.version != CAPTURE_VERSION {
+            return Err(CaptureError::UnsupportedVersion(header.version));
+        }
+        Ok(CaptureReader { reader, finished: false })
+    }
+
+    fn read_frame(&mut self) -> Result<Option<CaptureFrame>, CaptureError> {
+        // Distinguish clean end of file from a record cut off in the middle
+        let mut header = [0u8; 13];
+        let mut read_len = 0;
+        while read_len < header.len() {
+            match self.reader.read(&mut header[read_len..])? {
+                0 if read_len == 0 => return Ok(None),
+                0 => return Err(CaptureError::TruncatedRecord),
+                n => read_len += n,
+            }
+        }
+        let header: CaptureRecordHeader = capture_bincode_options().deserialize(&header)?;
+        let direction: FrameDirection = header.direction.try_into()
+            .map_err(|_| CaptureError::InvalidDirection(header.direction))?;
+        // Corrupt length must not allocate gigabytes
+        if header.length as usize > MAX_PACKET_SIZE {
+            return Err(CaptureError::FrameTooLarge(header.length));
+        }
+        let mut data = vec![0u8; header.length as usize];
+        self.reader.read_exact(&mut data).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => CaptureError::TruncatedRecord,
+            _ => CaptureError::IOError(e)
+        })?;
+        Ok(Some(CaptureFrame { timestamp_ms: header.timestamp_ms, direction, data }))
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = Result<CaptureFrame, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let result = self.read_frame().transpose();
+        // Stop at the first error, the rest of the file can not be trusted
+        if !matches!(result, Some(Ok(_))) {
+            self.finished = true;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::encoder::PacketEncoder;
+    use crate::packet::ws::Protover;
+
+    #[test]
+    fn certificate_is_redacted() {
+        let mut encoder = PacketEncoder::new();
+        encoder.heartbeat().unwrap();
+        let certificate = encoder.certificate(1939036, 4793604, "secret-token", Protover::Brotli).unwrap();
+
+        let redacted = redact_certificate(&certificate);
+        assert!(!redacted.windows(12).any(|window| window == b"secret-token"));
+        let packet = Packet::from_binary(&redacted).unwrap();
+        assert_eq!(packet.header.sequence, 2);
+        assert_eq!(packet.header.total_size as usize, redacted.len());
+        let body: CertificatePacketBody = serde_json::from_slice(&packet.body).unwrap();
+        assert_eq!((body.uid, body.roomid, body.key.as_str(), body.protover), (0, 4793604, "", Protover::Brotli as u8));
+    }
+
+    // Every test has its own capture file
+    fn capture_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("bilibili-live-danmaku-cli-capture-{}-{}.cap", std::process::id(), name));
+        path.to_string_lossy().to_string()
+    }
+
+    fn write_capture(name: &str) -> String {
+        let path = capture_path(name);
+        let mut encoder = PacketEncoder::new();
+        let writer = CaptureWriter::create(&path).unwrap();
+        writer.write_frame(FrameDirection::Outbound, &encoder.certificate(1939036, 4793604, "secret-token", Protover::Brotli).unwrap()).unwrap();
+        writer.write_frame(FrameDirection::Inbound, b"inbound frame").unwrap();
+        writer.write_frame(FrameDirection::Outbound, &encoder.heartbeat().unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn written_frames_are_read_back() {
+        let path = write_capture("round-trip");
+        let frames: Vec<CaptureFrame> = CaptureReader::open(&path).unwrap().map(Result::unwrap).collect();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(frames.len(), 3);
+        let directions: Vec<FrameDirection> = frames.iter().map(|frame| frame.direction).collect();
+        assert_eq!(directions, [FrameDirection::Outbound, FrameDirection::Inbound, FrameDirection::Outbound]);
+        assert!(frames.windows(2).all(|pair| pair[0].timestamp_ms <= pair[1].timestamp_ms));
+        // Certificate is stored redacted, other frames as they are
+        let certificate = Packet::from_binary(&frames[0].data).unwrap();
+        let body: CertificatePacketBody = serde_json::from_slice(&certificate.body).unwrap();
+        assert_eq!((body.uid, body.key.as_str()), (0, ""));
+        assert_eq!(frames[1].data, b"inbound frame");
+        assert_eq!(Packet::from_binary(&frames[2].data).unwrap().header.packet_type, PacketType::Heartbeat as u32);
+    }
+
+    #[test]
+    fn truncated_record_is_error() {
+        let path = write_capture("truncated");
+        let content = std::fs::read(&path).unwrap();
+        // Cut off in the data of the last frame, then in the header of it
+        let last_frame_len = PacketEncoder::new().heartbeat().unwrap().len();
+        for cut in [1, last_frame_len + 5] {
+            std::fs::write(&path, &content[..content.len() - cut]).unwrap();
+            let mut reader = CaptureReader::open(&path).unwrap();
+            assert!(reader.next().unwrap().is_ok());
+            assert!(reader.next().unwrap().is_ok());
+            assert!(matches!(reader.next(), Some(Err(CaptureError::TruncatedRecord))));
+            assert!(reader.next().is_none());
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn oversized_record_is_error() {
+        let path = capture_path("oversized");
+        let file_header = CaptureFileHeader { magic: CAPTURE_MAGIC, version: CAPTURE_VERSION, reserved: 0 };
+        let record_header = CaptureRecordHeader { timestamp_ms: 0, direction: 0, length: u32::MAX };
+        let mut content = capture_bincode_options().serialize(&file_header).unwrap();
+        content.extend(capture_bincode_options().serialize(&record_header).unwrap());
+        std::fs::write(&path, content).unwrap();
+
+        let mut reader = CaptureReader::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(reader.next(), Some(Err(CaptureError::FrameTooLarge(u32::MAX)))));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn other_frames_are_kept() {
+        let heartbeat = PacketEncoder::new().heartbeat().unwrap();
+        assert!(matches!(redact_certificate(&heartbeat), Cow::Borrowed(data) if data == heartbeat.as_slice()));
+        assert!(matches!(redact_certificate(b"garbage"), Cow::Borrowed(b"garbage")));
+    }
+}
@@ -1,20 +1,51 @@
+use std::collections::VecDeque;
 use std::error;
 use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
+use chrono::{DateTime, TimeDelta, Utc};
 use derive_more::Display;
 
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::HandshakeError;
 use tungstenite::stream::MaybeTlsStream;
 use tungstenite::{Message, WebSocket};
+use ureq::Proxy;
 
+use crate::capture::{CaptureWriter, FrameDirection};
 use crate::depack::{depack_packets, DepackedMessage};
+use crate::message::LiveMessage;
+use crate::packet::encoder::PacketEncoder;
+use crate::packet::stream::PacketDecoder;
 use crate::session_data::SessionData;
-use crate::Packet;
+use crate::packet::ws::Protover;
+use crate::proxy::connect_through;
 
 #[allow(unused)]
 pub struct LiveClient {
     client: WebSocket<MaybeTlsStream<TcpStream>>,
     connected: bool,
-    session: SessionData
+    session: SessionData,
+    capture: Option<CaptureWriter>,
+    receiver: FrameReceiver,
+    encoder: PacketEncoder,
+    shutdown: Option<Arc<AtomicBool>>
+}
+
+// Read timeout before the reader thread sets its own deadline, also the longest time to notice a shutdown request
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Time to wait for the server to acknowledge the close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Events sent from the reader thread
+pub enum ClientEvent {
+    Messages(Vec<DepackedMessage>),
+    Closed(Result<(), ClientError>)
 }
 
 #[derive(Debug, Display)]
@@ -22,7 +53,9 @@ pub enum ClientError {
     TungsteniteError(tungstenite::Error),
     IOError(std::io::Error),
     ConnectionClosed,
-    PacketProcessError
+    PacketProcessError,
+    AuthRejected(i32),
+    HeartbeatTimeout
 }
 
 impl error::Error for ClientError {
@@ -31,38 +64,107 @@ impl error::Error for ClientError {
             Self::TungsteniteError(e) => Some(e),
             Self::IOError(e) => Some(e),
             Self::ConnectionClosed => None,
-            Self::PacketProcessError => None
+            Self::PacketProcessError => None,
+            Self::AuthRejected(_) => None,
+            Self::HeartbeatTimeout => None
         }
     }
 }
 
 impl LiveClient {
-    pub fn connect(host_url: &str, session: SessionData) -> Result<Self, ClientError> {
+    pub fn connect(
+        host_url: &str,
+        session: SessionData,
+        protover: Protover,
+        capture: Option<CaptureWriter>,
+        proxy: Option<&Proxy>
+    ) -> Result<Self, ClientError> {
         
-        let (mut client, _) = tungstenite::connect(host_url)
-            .map_err(|e| ClientError::TungsteniteError(e))?;
-        // Make underlying stream non-blocking
-        match client.get_mut() {
-            MaybeTlsStream::Plain(stream) => {
-                stream.set_nonblocking(true)
-            },
-            MaybeTlsStream::Rustls(stream) => {
-                stream.get_mut().set_nonblocking(true)
-            },
-            _ => unimplemented!()
+        let client = match proxy {
+            Some(proxy) => connect_websocket_through(host_url, proxy)?,
+            None => tungstenite::connect(host_url)
+                .map_err(|e| ClientError::TungsteniteError(e))?.0
+        };
+        let mut live_client = LiveClient {
+            client,
+            session,
+            connected: true,
+            capture,
+            receiver: FrameReceiver::new(),
+            encoder: PacketEncoder::new(),
+            shutdown: None
+        };
+        // Reads block until data arrives or the read timeout elapses
+        live_client.set_read_timeout(READ_TIMEOUT)?;
+        let certificate = live_client.encoder
+            .certificate(live_client.session.uid, live_client.session.room_id, &live_client.session.token, protover)
+            .map_err(|_| ClientError::PacketProcessError)?;
+        live_client.send_message(Message::binary(certificate))?;
+        log::debug!(target: "client", "Certificate packet sent");
+
+        Ok(live_client)
+    }
+
+    /// Stop the reader loop and close the connection gracefully once the flag is set
+    pub fn with_shutdown(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.shutdown = Some(flag);
+        self
+    }
+
+    /// Send a close frame and wait for the server to acknowledge it, giving up after the timeout
+    pub fn close(&mut self, timeout: Duration) -> Result<(), ClientError> {
+        if !self.connected {
+            return Ok(());
         }
-        .map_err(|e| ClientError::IOError(e))?;
+        self.connected = false;
+        self.client.close(None).map_err(ClientError::TungsteniteError)?;
+        let deadline = Utc::now() + TimeDelta::from_std(timeout).unwrap_or_default();
+        // Frames still in flight are read and dropped until the close handshake completes
+        loop {
+            let remaining = (deadline - Utc::now()).to_std().unwrap_or_default();
+            if remaining.is_zero() {
+                log::debug!(target: "client", "Server did not acknowledge close frame in time");
+                return Ok(());
+            }
+            self.set_read_timeout(remaining)?;
+            match self.client.read() {
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed) => {
+                    log::debug!(target: "client", "Connection closed gracefully");
+                    return Ok(());
+                }
+                Err(tungstenite::Error::Io(e)) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(ClientError::TungsteniteError(e))
+            }
+        }
+    }
 
-        let certificate = Packet::new_certificate_packet(session.uid, session.room_id, &session.token)
-            .map_err(|_| ClientError::PacketProcessError)?
-            .to_binary()
-            .map_err(|_| ClientError::PacketProcessError)?;
+    /// Whether the server has accepted the certificate packet
+    pub fn certificate_accepted(&self) -> bool {
+        self.receiver.certificate_accepted
+    }
 
-        client.send(Message::binary(certificate))
-        .map_err(|e|ClientError::TungsteniteError(e))?;
-        log::debug!(target: "client", "Certificate packet sent");
+    /// Whether any heartbeat has been answered, i.e. the connection has proven to work
+    pub fn heartbeat_acknowledged(&self) -> bool {
+        self.receiver.heartbeat_acknowledged
+    }
+
+    fn shutdown_requested(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    fn capture_frame(&self, direction: FrameDirection, data: &[u8]) {
+        if let Some(capture) = &self.capture {
+            if let Err(e) = capture.write_frame(direction, data) {
+                log::warn!(target: "client", "Failed to write frame to capture file: {}", e);
+            }
+        }
+    }
 
-        Ok(LiveClient{ client, session, connected: true })
+    pub fn send_heartbeat(&mut self) -> Result<(), ClientError> {
+        let heartbeat = self.encoder.heartbeat()
+            .map_err(|_| ClientError::PacketProcessError)?;
+        self.send_message(Message::binary(heartbeat))
     }
 
     pub fn send_message(&mut self, message: Message) -> Result<(), ClientError> {
@@ -70,49 +172,310 @@ impl LiveClient {
             return Err(ClientError::ConnectionClosed)
         }
         log::debug!(target: "client", "Message send invoked");
+        if let Message::Binary(data) = &message {
+            self.capture_frame(FrameDirection::Outbound, data);
+        }
         self.client.send(message).map_err(|e| ClientError::TungsteniteError(e))
     }
 
+    /// Check if the connection is still alive. A half-open connection stays silent without any error,
+    /// so it is considered dead if no heartbeat response or no frame is received within the timeout.
+    pub fn check_alive(&self, timeout: TimeDelta) -> Result<(), ClientError> {
+        match self.receiver.alive(timeout) {
+            true => Ok(()),
+            false => Err(ClientError::HeartbeatTimeout)
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> Result<(), ClientError> {
+        // Zero duration is not a valid read timeout
+        let timeout = Some(timeout.max(Duration::from_millis(1)));
+        match self.client.get_mut() {
+            MaybeTlsStream::Plain(stream) => stream.set_read_timeout(timeout),
+            MaybeTlsStream::Rustls(stream) => stream.get_mut().set_read_timeout(timeout),
+            // Other TLS backends are not enabled
+            _ => Err(std::io::ErrorKind::Unsupported.into())
+        }
+        .map_err(ClientError::IOError)
+    }
+
+    /// Block until next WebSocket message arrives and return depacked messages in it.
+    /// Returns empty list if the read timeout elapsed without any message.
     pub fn recv_messages(&mut self) -> Result<Vec<DepackedMessage>, ClientError> {
-        let mut messages: Vec<DepackedMessage> = vec![];
-        // Read all packets
-        loop {
-            let msg = match self.client.read() {
-                Ok(x) => x,
-                Err(tungstenite::Error::Io(e)) => {
-                    // If would block, return success
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        return Ok(messages);
-                    }
-                    return Err(ClientError::TungsteniteError(tungstenite::Error::Io(e)));
-                },
-                Err(e) => {
-                    return Err(ClientError::TungsteniteError(e));
+        let msg = match self.client.read() {
+            Ok(x) => x,
+            Err(tungstenite::Error::Io(e)) => {
+                // Read timeout is reported as would block or timed out depending on platform
+                if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) {
+                    return Ok(vec![]);
                 }
-            };
-            if msg.is_close() {
+                return Err(ClientError::TungsteniteError(tungstenite::Error::Io(e)));
+            },
+            Err(tungstenite::Error::ConnectionClosed) => {
                 self.connected = false;
-                return Ok(messages);
+                return Err(ClientError::ConnectionClosed);
+            },
+            Err(e) => {
+                return Err(ClientError::TungsteniteError(e));
             }
+        };
+        if msg.is_close() {
+            self.connected = false;
+            return Ok(vec![]);
+        }
+
+        if let Message::Binary(data) = &msg {
+            self.capture_frame(FrameDirection::Inbound, data);
+        }
+        let result = self.receiver.receive(&msg);
+        if let Err(ClientError::AuthRejected(_)) = result {
+            self.connected = false;
+        }
+        result
+    }
+
+    /// Move the client into a reader thread. The thread blocks on the socket, sends heartbeats
+    /// on schedule and hands depacked messages over the returned channel.
+    pub fn spawn_reader(
+        mut self,
+        heartbeat_interval: TimeDelta,
+        heartbeat_timeout: TimeDelta
+    ) -> (Receiver<ClientEvent>, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let result = self.run_reader(&sender, heartbeat_interval, heartbeat_timeout);
+            // Receiver is gone if the listener has stopped, nothing to report then
+            let _ = sender.send(ClientEvent::Closed(result));
+        });
+        (receiver, handle)
+    }
+
+    /// Spawn the reader thread and iterate over live messages received from it.
+    /// The iterator ends when the connection is closed, or after yielding an error.
+    pub fn messages(self, heartbeat_interval: TimeDelta, heartbeat_timeout: TimeDelta) -> LiveMessages {
+        let (receiver, _) = self.spawn_reader(heartbeat_interval, heartbeat_timeout);
+        LiveMessages {
+            receiver,
+            pending: VecDeque::new(),
+            finished: false
+        }
+    }
 
-            let data = msg.into_data();
-            let packet = match Packet::from_binary(&data) {
+    /// Run the reader loop in current thread until the connection is closed, sending heartbeats on schedule
+    /// and depacked messages to the sender. Unlike `spawn_reader`, closing is reported by the return value only.
+    /// If a shutdown flag is set with `with_shutdown`, the connection is closed gracefully once it is raised.
+    pub fn run_reader(
+        &mut self,
+        sender: &Sender<ClientEvent>,
+        heartbeat_interval: TimeDelta,
+        heartbeat_timeout: TimeDelta
+    ) -> Result<(), ClientError> {
+        self.run_reader_with(
+            |messages| sender.send(ClientEvent::Messages(messages)).is_ok(),
+            heartbeat_interval,
+            heartbeat_timeout
+        )
+    }
+
+    /// Same as `run_reader`, but hands depacked messages to the callback, e.g. to tag them with the connection.
+    /// The reader stops if the callback returns false.
+    pub fn run_reader_with(
+        &mut self,
+        mut on_messages: impl FnMut(Vec<DepackedMessage>) -> bool,
+        heartbeat_interval: TimeDelta,
+        heartbeat_timeout: TimeDelta
+    ) -> Result<(), ClientError> {
+        let mut next_heartbeat = Utc::now() + heartbeat_interval;
+        loop {
+            if self.shutdown_requested() {
+                log::debug!(target: "client", "Shutdown requested, closing connection");
+                return self.close(CLOSE_TIMEOUT);
+            }
+            // Wake up no later than the next heartbeat, and often enough to notice shutdown requests
+            let until_heartbeat = (next_heartbeat - Utc::now()).to_std().unwrap_or_default();
+            self.set_read_timeout(until_heartbeat.min(READ_TIMEOUT))?;
+            let messages = match self.recv_messages() {
                 Ok(x) => x,
-                Err(e) => {
-                    log::debug!(target: "client", "Failed to parse binary packet: {}\nData: {}", e, hex::encode(data));
-                    continue; 
-                }
+                Err(ClientError::ConnectionClosed) => return Ok(()),
+                Err(e) => return Err(e)
             };
-            log::debug!(target: "client", "Received packet: {:?}", packet.header);
+            if !messages.is_empty() && !on_messages(messages) {
+                return Ok(());
+            }
+            if !self.connected {
+                return Ok(());
+            }
+            if Utc::now() >= next_heartbeat {
+                if let Err(e) = self.send_heartbeat() {
+                    log::warn!(target: "client", "Failed to send heartbeat packet:\n {}", e);
+                } else {
+                    log::debug!(target: "client", "Heartbeat packet sent");
+                }
+                next_heartbeat = Utc::now() + heartbeat_interval;
+            }
+            // Watchdog for dead connections
+            self.receiver.watchdog(heartbeat_timeout)?;
+        }
+    }
+}
 
-            let message = match depack_packets(packet.header, &packet.body) {
-                Ok(message) => message, 
-                Err(e) => {
-                    log::debug!(target: "client", "Failed to depack packets: {}\nBody: {}", e, hex::encode(packet.body));
-                    continue;
+/// Receiving side of a connection shared by `LiveClient` and `AsyncLiveClient`.
+/// Decodes received frames and keeps track of the connection health.
+pub(crate) struct FrameReceiver {
+    decoder: PacketDecoder,
+    last_frame_time: DateTime<Utc>,
+    last_heartbeat_resp_time: DateTime<Utc>,
+    certificate_accepted: bool,
+    heartbeat_acknowledged: bool,
+}
+
+impl FrameReceiver {
+    pub(crate) fn new() -> Self {
+        FrameReceiver {
+            decoder: PacketDecoder::new(),
+            last_frame_time: Utc::now(),
+            last_heartbeat_resp_time: Utc::now(),
+            certificate_accepted: false,
+            heartbeat_acknowledged: false,
+        }
+    }
+
+    /// Record a received WebSocket message and return depacked messages in it.
+    /// Returns `AuthRejected` if the server rejected the certificate.
+    pub(crate) fn receive(&mut self, msg: &Message) -> Result<Vec<DepackedMessage>, ClientError> {
+        self.last_frame_time = Utc::now();
+        // Only binary frames carry packets, ping or text payload would corrupt the buffered stream
+        let Message::Binary(data) = msg else {
+            return Ok(vec![]);
+        };
+        let messages = decode_binary_frame(&mut self.decoder, data);
+        for message in messages.iter() {
+            if let DepackedMessage::HeartbeatResp(_) = message {
+                self.last_heartbeat_resp_time = Utc::now();
+                self.heartbeat_acknowledged = true;
+            }
+            // Server will close the connection after rejecting the certificate
+            if let DepackedMessage::CertificateResp(resp) = message {
+                if resp.code != 0 {
+                    return Err(ClientError::AuthRejected(resp.code));
                 }
-            };
-            messages.push(message);
+                self.certificate_accepted = true;
+            }
+        }
+        Ok(messages)
+    }
+
+    /// A half-open connection stays silent without any error, so it is considered dead
+    /// if no heartbeat response or no frame is received within the timeout
+    pub(crate) fn alive(&self, timeout: TimeDelta) -> bool {
+        Utc::now() < self.watchdog_deadline(timeout)
+    }
+
+    /// Time the connection is considered dead at unless another heartbeat response arrives
+    pub(crate) fn watchdog_deadline(&self, timeout: TimeDelta) -> DateTime<Utc> {
+        self.last_heartbeat_resp_time.min(self.last_frame_time)
+            .checked_add_signed(timeout)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    /// Return `HeartbeatTimeout` and log the cause if the connection is considered dead
+    pub(crate) fn watchdog(&self, timeout: TimeDelta) -> Result<(), ClientError> {
+        if self.alive(timeout) {
+            return Ok(());
+        }
+        // Heartbeat responses are frames too, a silent connection has missed both
+        let cause = match Utc::now() - self.last_frame_time > timeout {
+            true => "No frame received",
+            false => "No heartbeat response received"
         };
+        log::warn!(target: "client", "{} in {} seconds, connection considered dead", cause, timeout.num_seconds());
+        Err(ClientError::HeartbeatTimeout)
     }
-}
\ No newline at end of file
+}
+
+// Open the WebSocket connection in a tunnel through the proxy, TLS is set up inside the tunnel for wss
+fn connect_websocket_through(host_url: &str, proxy: &Proxy) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, ClientError> {
+    let request = host_url.into_client_request().map_err(ClientError::TungsteniteError)?;
+    let host = request.uri().host()
+        .ok_or(ClientError::TungsteniteError(tungstenite::Error::Url(tungstenite::error::UrlError::NoHostName)))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = request.uri().port_u16().unwrap_or(match request.uri().scheme_str() {
+        Some("wss") => 443,
+        _ => 80
+    });
+    let stream = connect_through(proxy, &host, port).map_err(ClientError::IOError)?;
+    stream.set_nodelay(true).map_err(ClientError::IOError)?;
+    match tungstenite::client_tls(request, stream) {
+        Ok((client, _)) => Ok(client),
+        Err(HandshakeError::Failure(e)) => Err(ClientError::TungsteniteError(e)),
+        // Handshake on a blocking stream is never interrupted
+        Err(HandshakeError::Interrupted(_)) => Err(ClientError::ConnectionClosed)
+    }
+}
+
+/// Blocking iterator over live messages, created by `LiveClient::messages`
+pub struct LiveMessages {
+    receiver: Receiver<ClientEvent>,
+    pending: VecDeque<LiveMessage>,
+    finished: bool
+}
+
+impl Iterator for LiveMessages {
+    type Item = Result<LiveMessage, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Some(Ok(message));
+            }
+            if self.finished {
+                return None;
+            }
+            match self.receiver.recv() {
+                Ok(ClientEvent::Messages(messages)) => {
+                    for message in messages {
+                        self.pending.extend(message.into_live_messages());
+                    }
+                },
+                Ok(ClientEvent::Closed(result)) => {
+                    self.finished = true;
+                    if let Err(e) = result {
+                        return Some(Err(e));
+                    }
+                },
+                // Reader thread always reports before exiting, treat a lost channel as closed
+                Err(_) => self.finished = true
+            }
+        }
+    }
+}
+
+/// Feed a binary WebSocket frame into the decoder and depack every complete packet.
+/// Undecodable packets are logged and ignored.
+pub fn decode_binary_frame(decoder: &mut PacketDecoder, data: &[u8]) -> Vec<DepackedMessage> {
+    decoder.push(data);
+    let mut messages = vec![];
+    for packet in decoder.by_ref() {
+        let packet = match packet {
+            Ok(x) => x,
+            Err(e) => {
+                log::debug!(target: "client", "Failed to parse binary packet: {}\nData: {}", e, hex::encode(data));
+                continue;
+            }
+        };
+        log::debug!(target: "client", "Received packet: {:?}", packet.header);
+
+        match depack_packets(packet.header, &packet.body) {
+            Ok(message) => messages.push(message),
+            Err(e) => {
+                log::debug!(target: "client", "Failed to depack packets: {}\nBody: {}", e, hex::encode(packet.body));
+            }
+        }
+    }
+    if decoder.pending_len() > 0 {
+        log::debug!(target: "client", "{} bytes buffered for incomplete packet", decoder.pending_len());
+    }
+    messages
+}
@@ -1,13 +1,22 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::time::Duration;
 
 use rusqlite::{Connection, OpenFlags};
 use serde::Deserialize;
+use ureq::Proxy;
+
+use bilibili_live_danmaku_cli::api::{ApiClient, ApiConfig, DEFAULT_API_BASE_URL, DEFAULT_LIVE_API_BASE_URL, DEFAULT_USER_AGENT};
+use bilibili_live_danmaku_cli::packet::ws::Protover;
+use bilibili_live_danmaku_cli::wbi::default_cache_dir;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RawConfig {
     #[serde(rename = "roomId")]
-    pub room_id: u64,
+    pub room_id: Option<u64>,
+    #[serde(rename = "roomIds")]
+    pub room_ids: Option<Vec<u64>>,
     #[serde(rename = "uid")]
     pub uid: Option<u64>,
     pub sessdata: Option<String>,
@@ -23,6 +32,49 @@ pub struct RawConfig {
     pub poll_interval_ms: Option<u64>,
     #[serde(rename = "firefoxCookiesDatabase")]
     pub firefox_cookies_database_path: Option<String>,
+    pub protover: Option<Protover>,
+    #[serde(rename = "captureFile")]
+    pub capture_path: Option<String>,
+    #[serde(rename = "replayFile")]
+    pub replay_path: Option<String>,
+    #[serde(rename = "replaySpeed")]
+    pub replay_speed: Option<f64>,
+    #[serde(rename = "serverUrl")]
+    pub server_url: Option<String>,
+    #[serde(rename = "mockServer")]
+    pub mock_server_addr: Option<String>,
+    #[serde(rename = "mockFixtures")]
+    pub mock_fixtures_path: Option<String>,
+    #[serde(rename = "statusLine")]
+    pub status_line: Option<bool>,
+    #[serde(rename = "reconnectDelay")]
+    pub reconnect_delay_ms: Option<u64>,
+    #[serde(rename = "reconnectMaxDelay")]
+    pub reconnect_max_delay_ms: Option<u64>,
+    #[serde(rename = "reconnectMaxRetries")]
+    pub reconnect_max_retries: Option<u32>,
+    #[serde(rename = "refreshAfterFailures")]
+    pub refresh_after_failures: Option<u32>,
+    #[serde(rename = "heartbeatInterval")]
+    pub heartbeat_interval_sec: Option<u64>,
+    #[serde(rename = "heartbeatTimeout")]
+    pub heartbeat_timeout_sec: Option<u64>,
+    pub proxy: Option<String>,
+    #[serde(rename = "hotStandby")]
+    pub hot_standby: Option<bool>,
+    pub backfill: Option<bool>,
+    #[serde(rename = "liveApiBaseUrl")]
+    pub live_api_base_url: Option<String>,
+    #[serde(rename = "apiBaseUrl")]
+    pub api_base_url: Option<String>,
+    #[serde(rename = "userAgent")]
+    pub user_agent: Option<String>,
+    #[serde(rename = "httpTimeout")]
+    pub http_timeout_sec: Option<u64>,
+    #[serde(rename = "httpRetries")]
+    pub http_retries: Option<u32>,
+    #[serde(rename = "wbiCacheDir")]
+    pub wbi_cache_dir: Option<String>,
 }
 
 impl RawConfig {
@@ -38,11 +90,19 @@ impl RawConfig {
         if let Some(path) = path {
             return RawConfig::from_file(path);
         }
-        // room_id
-        let room_id: u64 = read_after(&args, vec!["--room-id"])
-            .expect("Room ID is required")
-            .parse()
-            .expect("Invalid room ID");
+        // replay file
+        let replay_path: Option<String> = read_after(&args, vec!["--replay"]).cloned();
+        let replay_speed: Option<f64> = read_after(&args, vec!["--replay-speed"])
+            .map(|speed| speed.parse().expect("Invalid replay speed"));
+        // mock server
+        let mock_server_addr: Option<String> = read_after(&args, vec!["--mock-server"]).cloned();
+        let mock_fixtures_path: Option<String> = read_after(&args, vec!["--mock-fixtures"]).cloned();
+        // room_ids, multiple rooms are separated by commas
+        let room_ids: Option<Vec<u64>> = read_after(&args, vec!["--room-id"])
+            .map(|room_ids| room_ids.split(',')
+                .map(|room_id| room_id.trim().parse().expect("Invalid room ID"))
+                .collect()
+            );
         // uid
         let uid: Option<u64> = read_after(&args, vec!["--uid"])
             .map(|uid| uid.parse().expect("Invalid user UID"));
@@ -72,9 +132,48 @@ impl RawConfig {
         // poll interval
         let poll_interval_ms: Option<u64> = read_after(&args, vec!["--poll-interval"])
             .map(|interval| interval.parse().expect("Invalid interval time"));
+        // protover
+        let protover: Option<Protover> = read_after(&args, vec!["--protover"])
+            .map(|protover| protover.as_str().try_into().expect("Invalid protover, expected normal, zlib or brotli"));
+        // capture file
+        let capture_path: Option<String> = read_after(&args, vec!["--capture"]).cloned();
+        // status line
+        let status_line: bool = args.contains(&"--status-line".to_string());
+        // reconnect backoff
+        let reconnect_delay_ms: Option<u64> = read_after(&args, vec!["--reconnect-delay"])
+            .map(|delay| delay.parse().expect("Invalid reconnect delay"));
+        let reconnect_max_delay_ms: Option<u64> = read_after(&args, vec!["--reconnect-max-delay"])
+            .map(|delay| delay.parse().expect("Invalid reconnect delay"));
+        let reconnect_max_retries: Option<u32> = read_after(&args, vec!["--max-retries"])
+            .map(|retries| retries.parse().expect("Invalid retry count"));
+        let refresh_after_failures: Option<u32> = read_after(&args, vec!["--refresh-after-failures"])
+            .map(|failures| failures.parse().expect("Invalid failure count"));
+        // heartbeat
+        let heartbeat_interval_sec: Option<u64> = read_after(&args, vec!["--heartbeat-interval"])
+            .map(|interval| interval.parse().expect("Invalid interval time"));
+        let heartbeat_timeout_sec: Option<u64> = read_after(&args, vec!["--heartbeat-timeout"])
+            .map(|timeout| timeout.parse().expect("Invalid timeout"));
+        // server url
+        let server_url: Option<String> = read_after(&args, vec!["--server-url"]).cloned();
+        // proxy
+        let proxy: Option<String> = read_after(&args, vec!["--proxy"]).cloned();
+        // hot standby connection
+        let hot_standby: bool = args.contains(&"--hot-standby".to_string());
+        // backfill after reconnecting
+        let backfill: bool = args.contains(&"--backfill".to_string());
+        // http api
+        let live_api_base_url: Option<String> = read_after(&args, vec!["--live-api-base-url"]).cloned();
+        let api_base_url: Option<String> = read_after(&args, vec!["--api-base-url"]).cloned();
+        let user_agent: Option<String> = read_after(&args, vec!["--user-agent"]).cloned();
+        let http_timeout_sec: Option<u64> = read_after(&args, vec!["--http-timeout"])
+            .map(|timeout| timeout.parse().expect("Invalid timeout"));
+        let http_retries: Option<u32> = read_after(&args, vec!["--http-retries"])
+            .map(|retries| retries.parse().expect("Invalid retry count"));
+        let wbi_cache_dir: Option<String> = read_after(&args, vec!["--wbi-cache-dir"]).cloned();
         // Construct
         RawConfig {
-            room_id,
+            room_id: None,
+            room_ids,
             uid,
             sessdata,
             gift_combo: Some(gift_combo),
@@ -82,13 +181,65 @@ impl RawConfig {
             repeat_superchat: Some(repeat_superchat),
             repeat_superchat_interval_sec,
             poll_interval_ms,
-            firefox_cookies_database_path: database_path
+            firefox_cookies_database_path: database_path,
+            protover,
+            capture_path,
+            replay_path,
+            replay_speed,
+            server_url,
+            mock_server_addr,
+            mock_fixtures_path,
+            status_line: Some(status_line),
+            reconnect_delay_ms,
+            reconnect_max_delay_ms,
+            reconnect_max_retries,
+            refresh_after_failures,
+            heartbeat_interval_sec,
+            heartbeat_timeout_sec,
+            proxy,
+            hot_standby: Some(hot_standby),
+            backfill: Some(backfill),
+            live_api_base_url,
+            api_base_url,
+            user_agent,
+            http_timeout_sec,
+            http_retries,
+            wbi_cache_dir
         }
     }
 }
 
 impl Into<Config> for RawConfig {
     fn into(self) -> Config {
+        if self.poll_interval_ms.is_some() {
+            log::warn!("Poll interval is deprecated and ignored, messages are now processed as they arrive.");
+        }
+
+        // roomId and roomIds can be used together, roomId comes first
+        let mut merged_room_ids: Vec<u64> = self.room_id.into_iter().collect();
+        for room_id in self.room_ids.unwrap_or_default() {
+            if !merged_room_ids.contains(&room_id) {
+                merged_room_ids.push(room_id);
+            }
+        }
+        // Room ID is not required when replaying a capture file or running mock server
+        if merged_room_ids.is_empty() && self.replay_path.is_none() && self.mock_server_addr.is_none() {
+            panic!("Room ID is required");
+        }
+
+        // Watchdog must leave time for the first heartbeat to be answered, or it fires on every connection
+        let heartbeat_interval_sec = self.heartbeat_interval_sec.unwrap_or(20).max(1);
+        let mut heartbeat_timeout_sec = self.heartbeat_timeout_sec.unwrap_or(60);
+        if heartbeat_timeout_sec <= heartbeat_interval_sec {
+            heartbeat_timeout_sec = heartbeat_interval_sec * 3;
+            log::warn!("Heartbeat timeout must be longer than heartbeat interval, using {} seconds instead.", heartbeat_timeout_sec);
+        }
+
+        let status_line_available = merged_room_ids.len() <= 1;
+        if self.status_line == Some(true) && !status_line_available {
+            log::warn!("Status line is not available when monitoring multiple rooms, ignored.");
+        }
+
         let mut sessdata = self.sessdata;
         if sessdata.is_none() { if let Some(path) = self.firefox_cookies_database_path {
 
@@ -110,29 +261,77 @@ impl Into<Config> for RawConfig {
             log::debug!("Using SESSDATA from firefox database.")
         }}
 
+        let proxy = self.proxy.map(|proxy| Proxy::new(&proxy).expect("Invalid proxy URL"));
+        // Every HTTP request shares the same client
+        let api = ApiClient::new(ApiConfig {
+            live_base_url:                          self.live_api_base_url.unwrap_or(DEFAULT_LIVE_API_BASE_URL.to_string()),
+            base_url:                               self.api_base_url.unwrap_or(DEFAULT_API_BASE_URL.to_string()),
+            user_agent:                             self.user_agent.unwrap_or(DEFAULT_USER_AGENT.to_string()),
+            timeout:                                Duration::from_secs(self.http_timeout_sec.unwrap_or(10)),
+            retries:                                self.http_retries.unwrap_or(2),
+            proxy:                                  proxy.clone(),
+            wbi_cache_dir:                          self.wbi_cache_dir.map(PathBuf::from).unwrap_or_else(default_cache_dir),
+        });
+
         Config {
-            room_id:                                self.room_id,
+            room_ids:                               merged_room_ids,
             uid:                                    self.uid,
             sessdata:                               sessdata,
             gift_combo:                             self.gift_combo.unwrap_or(false),
             gift_combo_interval_ms:                 self.gift_combo_interval_ms.unwrap_or(2000),
             repeat_superchat:                   self.repeat_superchat.unwrap_or(false),
             repeat_superchat_interval_sec:      self.repeat_superchat_interval_sec.unwrap_or(30),
-            poll_interval_ms:                       self.poll_interval_ms.unwrap_or(200),
+            protover:                               self.protover.unwrap_or(Protover::Brotli),
+            capture_path:                           self.capture_path,
+            replay_path:                            self.replay_path,
+            replay_speed:                           self.replay_speed.unwrap_or(1.0),
+            server_url:                             self.server_url,
+            mock_server_addr:                       self.mock_server_addr,
+            mock_fixtures_path:                     self.mock_fixtures_path,
+            status_line:                            self.status_line.unwrap_or(false) && status_line_available,
+            reconnect_delay_ms:                     self.reconnect_delay_ms.unwrap_or(1000),
+            reconnect_max_delay_ms:                 self.reconnect_max_delay_ms.unwrap_or(60000),
+            reconnect_max_retries:                  self.reconnect_max_retries,
+            refresh_after_failures:                 self.refresh_after_failures.unwrap_or(3).max(1),
+            heartbeat_interval_sec,
+            heartbeat_timeout_sec,
+            proxy,
+            hot_standby:                            self.hot_standby.unwrap_or(false),
+            backfill:                               self.backfill.unwrap_or(false),
+            api,
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct Config {
-    pub room_id: u64,
+    pub room_ids: Vec<u64>,
     pub uid: Option<u64>,
     pub sessdata: Option<String>,
     pub gift_combo: bool,
     pub gift_combo_interval_ms: u64,
     pub repeat_superchat: bool,
     pub repeat_superchat_interval_sec: u64,
-    pub poll_interval_ms: u64,
+    pub protover: Protover,
+    pub capture_path: Option<String>,
+    pub replay_path: Option<String>,
+    pub replay_speed: f64,
+    pub server_url: Option<String>,
+    pub mock_server_addr: Option<String>,
+    // Only read by mock server mode
+    #[cfg_attr(not(feature = "mock-server"), allow(unused))]
+    pub mock_fixtures_path: Option<String>,
+    pub status_line: bool,
+    pub reconnect_delay_ms: u64,
+    pub reconnect_max_delay_ms: u64,
+    pub reconnect_max_retries: Option<u32>,
+    pub refresh_after_failures: u32,
+    pub heartbeat_interval_sec: u64,
+    pub heartbeat_timeout_sec: u64,
+    pub proxy: Option<Proxy>,
+    pub hot_standby: bool,
+    pub backfill: bool,
+    pub api: ApiClient,
 }
 
 impl Config {
@@ -1,8 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use chrono::{DateTime, TimeDelta, Utc};
 
-use crate::message::{data::UserInfo, gift::SendGiftInfo, super_chat::SuperChatInfo};
+use bilibili_live_danmaku_cli::message::{data::UserInfo, gift::SendGiftInfo, guard::GuardBuyInfo, super_chat::SuperChatInfo};
+use bilibili_live_danmaku_cli::message::LiveMessage;
 
 #[derive(Debug, Clone)]
 pub struct CombinedSendGiftInfo {
@@ -14,8 +15,8 @@ pub struct CombinedSendGiftInfo {
 }
 
 impl CombinedSendGiftInfo {
-    pub fn expired(&self) -> bool {
-        return Utc::now() > self.expiry_time
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        return now > self.expiry_time
     }
 }
 
@@ -28,7 +29,7 @@ impl SendGiftList {
     pub fn contains_info(&self, info: &SendGiftInfo) -> bool{
         self.gifts.contains_key(&(info.user.uid, info.gift_name.clone()))
     }
-    pub fn append_gift(&mut self, info: SendGiftInfo, expire_interval: TimeDelta, refresh_time: bool) {
+    pub fn append_gift(&mut self, info: SendGiftInfo, expire_interval: TimeDelta, refresh_time: bool, now: DateTime<Utc>) {
         let key = (info.user.uid, info.gift_name.clone());
         let combined_info = self.gifts.entry(key).or_insert(
             CombinedSendGiftInfo { 
@@ -36,21 +37,21 @@ impl SendGiftList {
                 gift_name: info.gift_name.clone(),
                 gift_count: 0,
                 event_count: 0,
-                expiry_time: Utc::now().checked_add_signed(expire_interval)
+                expiry_time: now.checked_add_signed(expire_interval)
                     .expect("Failed to update time: Time out of range")
             }
         );
         combined_info.gift_count += info.count;
         combined_info.event_count += 1;
         if refresh_time {
-            combined_info.expiry_time = Utc::now().checked_add_signed(expire_interval)
+            combined_info.expiry_time = now.checked_add_signed(expire_interval)
                 .expect("Failed to update time: Time out of range")
         }
     }
-    /// Return expired info, this will remove expired info from the pending list
-    pub fn get_expired(&mut self) -> Vec<CombinedSendGiftInfo> {
+    /// Return info expired at `now`, this will remove expired info from the pending list
+    pub fn get_expired(&mut self, now: DateTime<Utc>) -> Vec<CombinedSendGiftInfo> {
         let expired_list: Vec<CombinedSendGiftInfo> = self.gifts.iter()
-            .filter(|(_, info)| info.expired())
+            .filter(|(_, info)| info.expired(now))
             .map(|(_, combined_info)| combined_info.clone())
             .collect();
         for expired_info in expired_list.iter() {
@@ -58,9 +59,17 @@ impl SendGiftList {
         }
         return expired_list;
     }
+    /// Return every pending info regardless of expiry time, used for flushing on shutdown
+    pub fn drain(&mut self) -> Vec<CombinedSendGiftInfo> {
+        self.gifts.drain().map(|(_, info)| info).collect()
+    }
     pub fn remove(&mut self, info: &CombinedSendGiftInfo) {
         self.gifts.remove(&(info.user.uid, info.gift_name.clone()));
     }
+    /// Earliest expiry time of pending gifts
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        self.gifts.values().map(|info| info.expiry_time).min()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -68,6 +77,7 @@ pub struct SuperChatPresistent {
     pub superchat_info: SuperChatInfo,
     pub send_time: DateTime<Utc>,
     pub next_show_time: DateTime<Utc>,
+    pub show_interval: TimeDelta,
 }
 
 impl SuperChatPresistent {
@@ -75,11 +85,11 @@ impl SuperChatPresistent {
         self.send_time.checked_add_signed(TimeDelta::seconds(self.superchat_info.keep_time as i64))
             .expect("Failed to calculate time: Time out of range")
     }
-    pub fn expired(&self) -> bool {
-        return Utc::now() > self.expiry_time()
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        return now > self.expiry_time()
     }
-    pub fn should_show(&self) -> bool {
-        return Utc::now() > self.next_show_time
+    pub fn should_show(&self, now: DateTime<Utc>) -> bool {
+        return now > self.next_show_time
     }
 }
 
@@ -89,49 +99,117 @@ pub struct SuperChatList {
 
 impl SuperChatList {
 
-    pub fn append_superchat(&mut self, info: SuperChatInfo, show_interval: TimeDelta) {
+    pub fn append_superchat(&mut self, info: SuperChatInfo, show_interval: TimeDelta, now: DateTime<Utc>) {
         let uid = info.user.uid;
-        let send_time = Utc::now();
+        let send_time = now;
         let next_show_time = send_time.checked_add_signed(show_interval).expect("Failed to update time");
         let presistent = SuperChatPresistent {
             superchat_info: info,
             send_time,
-            next_show_time
+            next_show_time,
+            show_interval
         };
         self.superchats.insert((uid, send_time), presistent);
     }
     /// Return superchats that need to show again & expired superchats. This will remove expired superchats.
-    pub fn get_should_show(&mut self) -> Vec<SuperChatPresistent> {
+    pub fn get_should_show(&mut self, now: DateTime<Utc>) -> Vec<SuperChatPresistent> {
         let should_show_list: Vec<SuperChatPresistent> = self.superchats.iter()
-            .filter(|(_, sc)| sc.expired() | sc.should_show())
+            .filter(|(_, sc)| sc.expired(now) | sc.should_show(now))
             .map(|(_, presistent)| presistent.clone())
             .collect();
-        // Remove expired in the should show list
+        // Remove expired in the should show list, schedule next show for others
         for info in should_show_list.iter() {
-            if info.expired() {
-                self.superchats.remove(&(info.superchat_info.user.uid, info.send_time));
+            let key = (info.superchat_info.user.uid, info.send_time);
+            if info.expired(now) {
+                self.superchats.remove(&key);
+            } else if let Some(sc) = self.superchats.get_mut(&key) {
+                sc.next_show_time = sc.next_show_time.checked_add_signed(sc.show_interval)
+                    .expect("Failed to update time");
             }
         }
         return should_show_list;
     }
+    /// Earliest time a superchat needs to show again or expires
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        self.superchats.values()
+            .map(|sc| sc.next_show_time.min(sc.expiry_time()))
+            .min()
+    }
     
 }
 
+/// Statistics of messages received in this run, printed on exit
+pub struct RunSummary {
+    pub start_time: DateTime<Utc>,
+    pub danmaku_count: u64,
+    pub speakers: HashSet<u64>,
+    // Total count of each gift
+    pub gifts: HashMap<String, u64>,
+    pub superchat_count: u64,
+    pub superchat_price: f64,
+    pub guards: Vec<GuardBuyInfo>,
+}
+
+impl RunSummary {
+    pub fn new() -> Self {
+        RunSummary {
+            start_time: Utc::now(),
+            danmaku_count: 0,
+            speakers: HashSet::new(),
+            gifts: HashMap::new(),
+            superchat_count: 0,
+            superchat_price: 0.0,
+            guards: vec![],
+        }
+    }
+    pub fn record(&mut self, message: &LiveMessage) {
+        match message {
+            LiveMessage::Danmaku(info) => {
+                self.danmaku_count += 1;
+                self.speakers.insert(info.user.uid);
+            }
+            LiveMessage::SendGift(info) => {
+                *self.gifts.entry(info.gift_name.clone()).or_insert(0) += info.count;
+            }
+            LiveMessage::SuperChat(info) => {
+                self.superchat_count += 1;
+                self.superchat_price += info.price;
+            }
+            LiveMessage::GuardBuy(info) => self.guards.push(info.clone()),
+            _ => {}
+        }
+    }
+}
+
 #[allow(unused)]
 pub struct LiveContext {
     pub gift_list: SendGiftList,
-    pub superchat_list: SuperChatList
+    pub superchat_list: SuperChatList,
+    pub popularity: Option<u64>,
+    pub summary: RunSummary,
+    // Prefix of every printed line, used to tell rooms apart when monitoring multiple rooms
+    pub label: String
 }
 
 impl LiveContext {
-    pub fn new() -> LiveContext {
+    /// Earliest time that context events need to be checked
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        match (self.gift_list.next_deadline(), self.superchat_list.next_deadline()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b)
+        }
+    }
+    pub fn new(label: String) -> LiveContext {
         LiveContext {
             gift_list: SendGiftList {
                 gifts: HashMap::new()
             },
             superchat_list: SuperChatList { 
                 superchats: HashMap::new()
-            }
+            },
+            popularity: None,
+            summary: RunSummary::new(),
+            label
         }
     }
 }
\ No newline at end of file
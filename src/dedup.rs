@@ -0,0 +1,282 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::Value;
+
+use crate::depack::DepackedMessage;
+use crate::message::RawLiveMessage;
+
+/// Stable identities of a raw message, the same message pushed to different connections has the same identities.
+/// Returns an empty list if the message carries no ID, see `content_identity` for those.
+/// A message may have more than one identity, it is a duplicate if any of them has been seen.
+pub fn message_identities(message: &RawLiveMessage) -> Vec<String> {
+    if message.cmd == "DANMU_MSG" {
+        if let Some(identities) = message.info.as_ref().and_then(|info| live_danmaku_identities(info)) {
+            return identities;
+        }
+    }
+    let data = message.data.as_ref();
+    let id = match message.cmd.as_str() {
+        "SUPER_CHAT_MESSAGE" | "SUPER_CHAT_MESSAGE_JP" => data.and_then(|data| data.get("id")).map(value_string),
+        "SEND_GIFT" => data.and_then(|data| data.get("tid")).map(value_string),
+        "GUARD_BUY" => data.and_then(|data| fields_string(data, &["uid", "start_time"])),
+        "INTERACT_WORD" => data.and_then(|data| fields_string(data, &["uid", "timestamp", "msg_type"])),
+        _ => None
+    };
+    id.map(|id| vec![format!("{}:{}", message.cmd, id)]).unwrap_or_default()
+}
+
+/// Identity made of the whole message content, for messages without ID.
+/// Identical content may legitimately repeat, so it is only compared between different connections.
+pub fn content_identity(message: &RawLiveMessage) -> String {
+    format!(
+        "{}:{}:{}:{}:{}",
+        message.cmd,
+        option_string(message.room_id.as_ref()),
+        message.msg.as_deref().unwrap_or(""),
+        message.info.as_ref().map(|info| Value::from(info.clone()).to_string()).unwrap_or_default(),
+        option_string(message.data.as_ref())
+    )
+}
+
+// Danmaku carries id_str in the extra JSON string and check token in info[9]
+fn live_danmaku_identities(info: &[Value]) -> Option<Vec<String>> {
+    let id_str = info.first()
+        .and_then(|meta| meta.get(15))
+        .and_then(|meta| meta.get("extra"))
+        .and_then(|extra| extra.as_str())
+        .and_then(|extra| serde_json::from_str::<Value>(extra).ok())
+        .and_then(|extra| extra.get("id_str").and_then(|id| id.as_str()).map(|id| id.to_string()));
+    let check_info = info.get(9);
+    let check_token = check_info.and_then(|check| check.get("ct")).and_then(|ct| ct.as_str());
+    let timestamp = check_info.and_then(|check| check.get("ts")).and_then(|ts| ts.as_u64())?;
+    let uid = info.get(2)?.get(0)?.as_u64()?;
+    let text = info.get(1)?.as_str()?;
+    Some(danmaku_identities(id_str.as_deref(), check_token, timestamp, uid, text))
+}
+
+/// Identities of a danmaku, shared by live danmaku and danmaku fetched from history.
+/// Danmaku without ID and check token is identified by send time in seconds, sender and text.
+pub fn danmaku_identities(
+    id_str: Option<&str>,
+    check_token: Option<&str>,
+    timestamp_sec: u64,
+    uid: u64,
+    text: &str
+) -> Vec<String> {
+    let mut identities = vec![];
+    if let Some(id) = id_str.filter(|id| !id.is_empty()) {
+        identities.push(format!("DANMU_MSG:id:{}", id));
+    }
+    if let Some(ct) = check_token.filter(|ct| !ct.is_empty()) {
+        identities.push(format!("DANMU_MSG:ct:{}", ct));
+    }
+    if identities.is_empty() {
+        identities.push(format!("DANMU_MSG:{}:{}:{}", timestamp_sec, uid, text));
+    }
+    identities
+}
+
+// Join fields of data, all of them are required
+fn fields_string(data: &Value, keys: &[&str]) -> Option<String> {
+    let values: Option<Vec<String>> = keys.iter().map(|key| data.get(key).map(value_string)).collect();
+    values.map(|values| values.join(":"))
+}
+
+fn value_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string()
+    }
+}
+
+fn option_string(value: Option<&Value>) -> String {
+    value.map(value_string).unwrap_or_default()
+}
+
+// Occurrences of a message content on each connection
+struct ContentRecord {
+    counts: HashMap<usize, u64>,
+    last_seen: DateTime<Utc>,
+}
+
+/// Drops messages seen within the time window, used to merge streams of connections to the same room.
+///
+/// Messages with IDs are dropped if seen from any connection. Messages without ID are compared by content
+/// and counted per connection, a copy is dropped only if another connection has delivered it as many times,
+/// so identical messages repeated on the same connection are kept.
+pub struct MessageDeduplicator {
+    window: TimeDelta,
+    // Only danmaku are checked, other messages pass through unrecorded
+    danmaku_only: bool,
+    seen: HashSet<String>,
+    // Identities in the order they are first seen, for expiring
+    order: VecDeque<(DateTime<Utc>, String)>,
+    contents: HashMap<String, ContentRecord>,
+    // Content identities in the order they are seen, for expiring
+    content_order: VecDeque<(DateTime<Utc>, String)>,
+}
+
+impl MessageDeduplicator {
+    pub fn new(window: TimeDelta) -> Self {
+        MessageDeduplicator {
+            window,
+            danmaku_only: false,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            contents: HashMap::new(),
+            content_order: VecDeque::new(),
+        }
+    }
+
+    /// Deduplicator that only checks danmaku, e.g. against danmaku backfilled from history
+    pub fn danmaku_only(window: TimeDelta) -> Self {
+        MessageDeduplicator {
+            danmaku_only: true,
+            ..MessageDeduplicator::new(window)
+        }
+    }
+
+    fn expire(&mut self, now: DateTime<Utc>) {
+        while let Some((time, _)) = self.order.front() {
+            if now - *time <= self.window {
+                break;
+            }
+            if let Some((_, identity)) = self.order.pop_front() {
+                self.seen.remove(&identity);
+            }
+        }
+        while let Some((time, _)) = self.content_order.front() {
+            if now - *time <= self.window {
+                break;
+            }
+            if let Some((time, identity)) = self.content_order.pop_front() {
+                // Keep the record if the content has been seen again since
+                if self.contents.get(&identity).is_some_and(|record| record.last_seen <= time) {
+                    self.contents.remove(&identity);
+                }
+            }
+        }
+    }
+
+    /// Record the message received from the connection and check if it has been seen before
+    pub fn is_duplicate(&mut self, connection: usize, message: &RawLiveMessage) -> bool {
+        if self.danmaku_only && message.cmd != "DANMU_MSG" {
+            return false;
+        }
+        let identities = message_identities(message);
+        if identities.is_empty() {
+            return self.is_duplicate_content(connection, content_identity(message));
+        }
+        self.is_duplicate_identities(&identities)
+    }
+
+    fn is_duplicate_content(&mut self, connection: usize, identity: String) -> bool {
+        let now = Utc::now();
+        self.expire(now);
+        let record = self.contents.entry(identity.clone()).or_insert_with(|| ContentRecord {
+            counts: HashMap::new(),
+            last_seen: now,
+        });
+        record.last_seen = now;
+        let count = record.counts.entry(connection).or_insert(0);
+        *count += 1;
+        let count = *count;
+        let other_count = record.counts.iter()
+            .filter(|(other, _)| **other != connection)
+            .map(|(_, count)| *count)
+            .max()
+            .unwrap_or(0);
+        self.content_order.push_back((now, identity));
+        count <= other_count
+    }
+
+    /// Record identities of a message and check if any of them has been seen before
+    pub fn is_duplicate_identities(&mut self, identities: &[String]) -> bool {
+        let now = Utc::now();
+        self.expire(now);
+        let duplicate = identities.iter().any(|identity| self.seen.contains(identity));
+        // Record every identity, so later copies only carrying some of them are still recognized
+        for identity in identities {
+            if self.seen.insert(identity.clone()) {
+                self.order.push_back((now, identity.clone()));
+            }
+        }
+        duplicate
+    }
+
+    /// Remove duplicated live messages from depacked message received from the connection,
+    /// other kinds of messages are kept as is
+    pub fn filter(&mut self, connection: usize, message: DepackedMessage) -> DepackedMessage {
+        match message {
+            DepackedMessage::LiveMessages(messages) => {
+                let messages = messages.into_iter()
+                    .filter(|message| {
+                        let duplicate = self.is_duplicate(connection, message);
+                        if duplicate {
+                            log::debug!(target: "dedup", "Dropped duplicated message {}", message.cmd);
+                        }
+                        !duplicate
+                    })
+                    .collect();
+                DepackedMessage::LiveMessages(messages)
+            }
+            other => other
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn raw(message: Value) -> RawLiveMessage {
+        serde_json::from_value(message).unwrap()
+    }
+
+    fn deduplicator() -> MessageDeduplicator {
+        MessageDeduplicator::new(TimeDelta::seconds(300))
+    }
+
+    #[test]
+    fn messages_with_id_are_dropped_from_any_connection() {
+        let gift = raw(json!({ "cmd": "SEND_GIFT", "data": { "tid": "1234" } }));
+        let mut deduplicator = deduplicator();
+        assert!(!deduplicator.is_duplicate(0, &gift));
+        assert!(deduplicator.is_duplicate(1, &gift));
+        assert!(deduplicator.is_duplicate(0, &gift));
+    }
+
+    #[test]
+    fn repeated_content_on_one_connection_is_kept() {
+        let warning = raw(json!({ "cmd": "WARNING", "msg": "warning" }));
+        let mut deduplicator = deduplicator();
+        assert!(!deduplicator.is_duplicate(0, &warning));
+        assert!(!deduplicator.is_duplicate(0, &warning));
+    }
+
+    #[test]
+    fn content_is_dropped_once_per_copy_from_other_connection() {
+        let warning = raw(json!({ "cmd": "WARNING", "msg": "warning" }));
+        let mut deduplicator = deduplicator();
+        // The warning is sent twice, both connections deliver both copies
+        let duplicates: Vec<bool> = [0, 1, 0, 1].iter()
+            .map(|connection| deduplicator.is_duplicate(*connection, &warning))
+            .collect();
+        assert_eq!(duplicates, [false, true, false, true]);
+    }
+
+    #[test]
+    fn danmaku_only_ignores_other_commands() {
+        let gift = raw(json!({ "cmd": "SEND_GIFT", "data": { "tid": "1234" } }));
+        let mut deduplicator = MessageDeduplicator::danmaku_only(TimeDelta::seconds(300));
+        assert!(!deduplicator.is_duplicate(0, &gift));
+        assert!(!deduplicator.is_duplicate(1, &gift));
+
+        let identities = danmaku_identities(Some("d1"), None, 1700000000, 5, "hello");
+        assert!(!deduplicator.is_duplicate_identities(&identities));
+        assert!(deduplicator.is_duplicate_identities(&identities));
+    }
+}
@@ -1,14 +1,44 @@
 use std::io::Read;
 use derive_more::Display;
+use flate2::read::ZlibDecoder;
 
-use crate::{message::RawLiveMessage, Packet, PacketConvertError, PacketHeader, PacketType, Protocol};
+use crate::message::popularity::PopularityInfo;
+use crate::message::{LiveMessage, RawMessageDeserializeError};
+use crate::message::RawLiveMessage;
+use crate::packet::ws::{CertificateRespBody, Packet, PacketConvertError, PacketHeader, PacketType, Protocol};
 
 pub enum DepackedMessage {
-    CertificateResp,
+    CertificateResp(CertificateRespBody),
     HeartbeatResp(u64),
     LiveMessages(Vec<RawLiveMessage>)
 }
 
+impl DepackedMessage {
+    /// Convert into live messages, heartbeat response becomes popularity message.
+    /// Unsupported or malformed commands are logged and skipped.
+    pub fn into_live_messages(self) -> Vec<LiveMessage> {
+        let messages = match self {
+            DepackedMessage::CertificateResp(_) => return vec![],
+            DepackedMessage::HeartbeatResp(count) => return vec![LiveMessage::Popularity(PopularityInfo::new(count))],
+            DepackedMessage::LiveMessages(messages) => messages
+        };
+        let mut live_messages = vec![];
+        for raw_message in messages {
+            match LiveMessage::try_from(raw_message) {
+                Ok(x) => live_messages.push(x),
+                Err(RawMessageDeserializeError::NotSupported(cmd)) => {
+                    log::debug!(target: "msg_process", "Ignored unsupported command type {:#?}", cmd);
+                },
+                Err(RawMessageDeserializeError::DeserializeError(message)) => {
+                    log::warn!(target: "msg_process", "Failed to deserialize raw message into live message");
+                    log::warn!(target: "msg_process", "Live message: {}", message);
+                }
+            };
+        }
+        live_messages
+    }
+}
+
 #[derive(Debug, Display)]
 pub enum PacketDepackError {
     InvalidProtocol,
@@ -59,7 +89,9 @@ pub fn depack_packets(header: PacketHeader, raw_body: &[u8]) -> Result<DepackedM
                 .map_err(|_| PacketDepackError::DecompressError)?;
         }
         Protocol::CommandZlib => {
-            unimplemented!("Zlib compression is not currently supported.");
+            ZlibDecoder::new(raw_body)
+                .read_to_end(&mut body)
+                .map_err(|_| PacketDepackError::DecompressError)?;
         }
         Protocol::Command | Protocol::Special => {
             body.extend_from_slice(raw_body);
@@ -70,7 +102,9 @@ pub fn depack_packets(header: PacketHeader, raw_body: &[u8]) -> Result<DepackedM
         // Single command or special command
         Protocol::Special | Protocol::Command => {
             if header.packet_type == PacketType::CertificateResp as u32 {
-            return Ok(DepackedMessage::CertificateResp)
+                let resp: CertificateRespBody = serde_json::from_slice(&body)
+                    .map_err(|_| PacketDepackError::BodyDeserializeError)?;
+                return Ok(DepackedMessage::CertificateResp(resp))
             } else if header.packet_type == PacketType::HeartbeatResp as u32 {
                 if body.len() < 4 {
                     return Err(PacketDepackError::BodyDeserializeError)
@@ -110,4 +144,74 @@ pub fn depack_packets(header: PacketHeader, raw_body: &[u8]) -> Result<DepackedM
             return Ok(DepackedMessage::LiveMessages(live_messages))
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+
+    use super::*;
+
+    // Synthetic protover 2 command frame with a danmaku, a gift and a warning inside, laid out like
+    // a server frame (zlib body of 16-byte-header inner packets) but with made up users, text and check token
+    const ZLIB_COMMAND_FRAME: &[u8] = include_bytes!("../tests/fixtures/zlib_command_frame.bin");
+
+    fn zlib_packet(inner: &[u8]) -> Packet {
+        let mut writer = ZlibEncoder::new(vec![], flate2::Compression::default());
+        writer.write_all(inner).unwrap();
+        Packet::create(Protocol::CommandZlib, PacketType::Command, 0, writer.finish().unwrap())
+    }
+
+    #[test]
+    fn zlib_frame_splits_into_inner_commands() {
+        let packet = Packet::from_binary(ZLIB_COMMAND_FRAME).unwrap();
+        assert_eq!(packet.header.protocol, Protocol::CommandZlib as u16);
+
+        let messages = match depack_packets(packet.header, &packet.body).unwrap() {
+            DepackedMessage::LiveMessages(messages) => messages,
+            _ => panic!("expected live messages"),
+        };
+        let commands: Vec<&str> = messages.iter().map(|message| message.cmd.as_str()).collect();
+        assert_eq!(commands, ["DANMU_MSG", "SEND_GIFT", "WARNING"]);
+
+        let live_messages = DepackedMessage::LiveMessages(messages).into_live_messages();
+        assert!(matches!(&live_messages[0], LiveMessage::Danmaku(info) if info.text == "hello"));
+        assert!(matches!(&live_messages[1], LiveMessage::SendGift(info) if info.count == 3));
+    }
+
+    #[test]
+    fn malformed_zlib_body_is_decompress_error() {
+        let packet = Packet::from_binary(ZLIB_COMMAND_FRAME).unwrap();
+        let truncated = &packet.body[..packet.body.len() / 2];
+        assert!(matches!(
+            depack_packets(packet.header.clone(), truncated),
+            Err(PacketDepackError::DecompressError)
+        ));
+        assert!(matches!(
+            depack_packets(packet.header, b"definitely not zlib"),
+            Err(PacketDepackError::DecompressError)
+        ));
+    }
+
+    #[test]
+    fn inner_packet_shorter_than_header_is_error() {
+        // Header claims 8 bytes in total, reading it must not loop on the same offset
+        let mut inner = Packet::create(Protocol::Command, PacketType::Command, 0, b"{}".to_vec());
+        inner.header.total_size = 8;
+        let packet = zlib_packet(&inner.to_binary().unwrap());
+        assert!(matches!(
+            depack_packets(packet.header, &packet.body),
+            Err(PacketDepackError::PacketConvertError(PacketConvertError::HeaderSizeError))
+        ));
+
+        inner.header.total_size = 0;
+        inner.header.head_size = 0;
+        let packet = zlib_packet(&inner.to_binary().unwrap());
+        assert!(matches!(
+            depack_packets(packet.header, &packet.body),
+            Err(PacketDepackError::PacketConvertError(PacketConvertError::HeaderSizeError))
+        ));
+    }
+}
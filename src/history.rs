@@ -0,0 +1,101 @@
+use chrono::{DateTime, Utc};
+use derive_more::Display;
+use serde_json::Value;
+
+use crate::api::ApiClient;
+use crate::dedup::danmaku_identities;
+use crate::message::danmaku::DanmakuInfo;
+use crate::message::data::UserInfo;
+
+/// Danmaku fetched from the recent history endpoint of a room
+#[derive(Debug, Clone)]
+pub struct HistoryDanmaku {
+    pub info: DanmakuInfo,
+    pub send_time: DateTime<Utc>,
+    // Identities for deduplicating against live danmaku
+    pub identities: Vec<String>,
+}
+
+#[derive(Debug, Display)]
+pub enum HistoryError {
+    UreqError(ureq::Error),
+    BadResponse,
+    #[display(fmt = "API error (code {}): {}", code, message)]
+    Api { code: isize, message: String },
+}
+
+impl std::error::Error for HistoryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self {
+            Self::UreqError(e) => Some(e),
+            Self::BadResponse => None,
+            Self::Api { .. } => None,
+        }
+    }
+}
+
+impl From<ureq::Error> for HistoryError {
+    fn from(err: ureq::Error) -> Self {
+        HistoryError::UreqError(err)
+    }
+}
+
+/// Fetch recent danmaku of the room, oldest first.
+/// Entries that can not be parsed are skipped.
+pub fn fetch_danmaku_history(api: &ApiClient, room_id: u64) -> Result<Vec<HistoryDanmaku>, HistoryError> {
+    let response = api.get_json(
+        &api.live_url(&format!("/xlive/web-room/v1/dM/gethistory?roomid={room_id}&room_type=0")),
+        &[("Referer", "https://live.bilibili.com/")]
+    )?;
+    if !response.ok() {
+        return Err(HistoryError::Api { code: response.code(), message: response.message().to_string() });
+    }
+    let data = response.response_data();
+
+    let entries = data.get("room")
+        .and_then(|room| room.as_array())
+        .ok_or(HistoryError::BadResponse)?;
+    let history = entries.iter()
+        .filter_map(|entry| {
+            let danmaku = parse_history_entry(entry);
+            if danmaku.is_none() {
+                log::debug!(target: "history", "Failed to parse history entry: {}", entry);
+            }
+            danmaku
+        })
+        .collect();
+    Ok(history)
+}
+
+fn parse_history_entry(entry: &Value) -> Option<HistoryDanmaku> {
+    let text = entry.get("text")?.as_str()?;
+    let uid = entry.get("uid")?.as_u64()?;
+    let check_info = entry.get("check_info");
+    let timestamp = check_info.and_then(|check| check.get("ts")).and_then(|ts| ts.as_u64())?;
+    let check_token = check_info.and_then(|check| check.get("ct")).and_then(|ct| ct.as_str());
+    let id_str = entry.get("id_str").and_then(|id| id.as_str());
+
+    // Newer responses carry the same user info as live danmaku, older ones only have nickname
+    let guard_level = entry.get("guard_level").and_then(|level| level.as_u64()).and_then(|level| level.try_into().ok());
+    let user = match entry.get("user").and_then(UserInfo::try_from) {
+        Some(user) => user.set_guard(guard_level),
+        None => UserInfo {
+            uid,
+            username: entry.get("nickname")?.as_str()?.to_string(),
+            guard_level,
+            medal: None,
+        }
+    };
+
+    Some(HistoryDanmaku {
+        info: DanmakuInfo {
+            user,
+            is_admin: entry.get("isadmin").and_then(|admin| admin.as_u64()).is_some_and(|admin| admin == 1),
+            is_vip: entry.get("vip").and_then(|vip| vip.as_u64()).is_some_and(|vip| vip == 1),
+            text: text.to_string(),
+            backfilled: true,
+        },
+        send_time: DateTime::from_timestamp(timestamp as i64, 0)?,
+        identities: danmaku_identities(id_str, check_token, timestamp, uid, text),
+    })
+}
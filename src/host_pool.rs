@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::packet::http::WebsocketHost;
+
+/// Rotates through WebSocket hosts returned by getDanmuInfo, skipping hosts that failed recently
+pub struct HostPool {
+    // Host and candidate URL in preferred order, wss before ws for every host
+    candidates: Vec<(String, String)>,
+    current: usize,
+    failures: HashMap<String, DateTime<Utc>>,
+    failure_cooldown: TimeDelta,
+}
+
+impl HostPool {
+    pub fn new(hosts: &[WebsocketHost], failure_cooldown: TimeDelta) -> Self {
+        let mut pool = HostPool {
+            candidates: vec![],
+            current: 0,
+            failures: HashMap::new(),
+            failure_cooldown,
+        };
+        pool.replace_hosts(hosts);
+        pool
+    }
+
+    /// Replace hosts with a refreshed host list, failure records are kept
+    pub fn replace_hosts(&mut self, hosts: &[WebsocketHost]) {
+        self.candidates = hosts.iter()
+            .flat_map(|host| [
+                (host.host.clone(), format!("wss://{}:{}/sub", host.host, host.wss_port)),
+                (host.host.clone(), format!("ws://{}:{}/sub", host.host, host.ws_port)),
+            ])
+            .collect();
+        self.current = 0;
+    }
+
+    fn failed_recently(&self, url: &str) -> bool {
+        self.failures.get(url).is_some_and(|time| Utc::now() - *time < self.failure_cooldown)
+    }
+
+    /// Return current host URL if it is usable, otherwise rotate to the next usable one.
+    /// Returns `None` if every host failed recently.
+    pub fn select(&mut self) -> Option<String> {
+        self.select_where(|_| true)
+    }
+
+    /// Same as `select`, but prefer hosts not used by the excluded URLs.
+    /// Falls back to any usable host if every other host failed recently.
+    pub fn select_excluding(&mut self, excluded: &[String]) -> Option<String> {
+        let excluded_hosts: Vec<String> = self.candidates.iter()
+            .filter(|(_, url)| excluded.contains(url))
+            .map(|(host, _)| host.clone())
+            .collect();
+        self.select_where(|host| !excluded_hosts.iter().any(|excluded| excluded == host))
+            .or_else(|| self.select())
+    }
+
+    fn select_where(&mut self, accept_host: impl Fn(&str) -> bool) -> Option<String> {
+        let count = self.candidates.len();
+        for offset in 0..count {
+            let index = (self.current + offset) % count;
+            let (host, url) = &self.candidates[index];
+            if accept_host(host) && !self.failed_recently(url) {
+                self.current = index;
+                return Some(url.clone());
+            }
+        }
+        None
+    }
+
+    pub fn mark_failed(&mut self, url: &str) {
+        self.failures.insert(url.to_string(), Utc::now());
+    }
+
+    pub fn mark_succeeded(&mut self, url: &str) {
+        self.failures.remove(url);
+    }
+
+    /// Forget all failures, used when the host list can not be refreshed
+    pub fn clear_failures(&mut self) {
+        self.failures.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn host(name: &str) -> WebsocketHost {
+        WebsocketHost { host: name.to_string(), port: 2243, wss_port: 443, ws_port: 2244 }
+    }
+
+    fn host_pool(names: &[&str]) -> HostPool {
+        let hosts: Vec<WebsocketHost> = names.iter().map(|name| host(name)).collect();
+        HostPool::new(&hosts, TimeDelta::seconds(60))
+    }
+
+    #[test]
+    fn wss_is_tried_before_ws() {
+        let mut pool = host_pool(&["a"]);
+        assert_eq!(pool.select().unwrap(), "wss://a:443/sub");
+        // Current host is kept until it fails
+        assert_eq!(pool.select().unwrap(), "wss://a:443/sub");
+        pool.mark_failed("wss://a:443/sub");
+        assert_eq!(pool.select().unwrap(), "ws://a:2244/sub");
+        pool.mark_failed("ws://a:2244/sub");
+        assert!(pool.select().is_none());
+    }
+
+    #[test]
+    fn failed_host_is_rotated() {
+        let mut pool = host_pool(&["a", "b"]);
+        pool.mark_failed("wss://a:443/sub");
+        pool.mark_failed("ws://a:2244/sub");
+        assert_eq!(pool.select().unwrap(), "wss://b:443/sub");
+        // Succeeded host is usable again
+        pool.mark_failed("wss://b:443/sub");
+        pool.mark_failed("ws://b:2244/sub");
+        pool.mark_succeeded("wss://a:443/sub");
+        assert_eq!(pool.select().unwrap(), "wss://a:443/sub");
+    }
+
+    #[test]
+    fn failures_expire_after_cooldown() {
+        let mut pool = HostPool::new(&[host("a")], TimeDelta::milliseconds(50));
+        pool.mark_failed("wss://a:443/sub");
+        pool.mark_failed("ws://a:2244/sub");
+        assert!(pool.select().is_none());
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pool.select().unwrap(), "wss://a:443/sub");
+
+        pool.mark_failed("wss://a:443/sub");
+        pool.clear_failures();
+        assert_eq!(pool.select().unwrap(), "wss://a:443/sub");
+    }
+
+    #[test]
+    fn excluded_host_is_avoided_unless_it_is_the_only_one() {
+        let mut pool = host_pool(&["a", "b"]);
+        let excluded = vec!["wss://a:443/sub".to_string()];
+        // Both URLs of the excluded host are avoided
+        assert_eq!(pool.select_excluding(&excluded).unwrap(), "wss://b:443/sub");
+
+        pool.mark_failed("wss://b:443/sub");
+        pool.mark_failed("ws://b:2244/sub");
+        assert_eq!(pool.select_excluding(&excluded).unwrap(), "wss://a:443/sub");
+
+        // Hot standby shares the only host
+        let mut single = host_pool(&["a"]);
+        assert_eq!(single.select_excluding(&excluded).unwrap(), "wss://a:443/sub");
+    }
+
+    #[test]
+    fn replaced_hosts_keep_failures() {
+        let mut pool = host_pool(&["a"]);
+        pool.mark_failed("wss://a:443/sub");
+        pool.replace_hosts(&[host("a"), host("c")]);
+        assert_eq!(pool.select().unwrap(), "ws://a:2244/sub");
+        pool.mark_failed("ws://a:2244/sub");
+        assert_eq!(pool.select().unwrap(), "wss://c:443/sub");
+    }
+}
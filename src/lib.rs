@@ -0,0 +1,48 @@
+//! Client library for Bilibili live danmaku WebSocket, used by the `bilibili-live-danmaku-cli` binary.
+//!
+//! A minimal listener fetches the session data of a room, connects to one of its hosts and
+//! iterates over live messages:
+//!
+//! ```no_run
+//! use bilibili_live_danmaku_cli::{init_room_data, ApiClient, ApiConfig, LiveClient, LiveMessage, Protover};
+//! use chrono::TimeDelta;
+//!
+//! let api = ApiClient::new(ApiConfig::default());
+//! let (session, hosts) = init_room_data(1, None, &None, &api).unwrap();
+//! let host = &hosts[0];
+//! let url = format!("wss://{}:{}/sub", host.host, host.wss_port);
+//! let client = LiveClient::connect(&url, session, Protover::Brotli, None, None).unwrap();
+//! for message in client.messages(TimeDelta::seconds(20), TimeDelta::seconds(60)) {
+//!     if let Ok(LiveMessage::Danmaku(info)) = message {
+//!         println!("{}: {}", info.user.username, info.text);
+//!     }
+//! }
+//! ```
+
+pub mod api;
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod backoff;
+pub mod capture;
+pub mod client;
+pub mod dedup;
+pub mod depack;
+pub mod history;
+pub mod host_pool;
+pub mod message;
+#[cfg(feature = "mock-server")]
+pub mod mock_server;
+pub mod packet;
+pub mod proxy;
+pub mod session_data;
+pub mod wbi;
+
+#[cfg(feature = "async")]
+pub use async_client::AsyncLiveClient;
+pub use api::{ApiClient, ApiConfig};
+pub use client::{ClientError, ClientEvent, LiveClient, LiveMessages};
+pub use depack::DepackedMessage;
+pub use message::LiveMessage;
+pub use packet::http::WebsocketHost;
+pub use packet::ws::Protover;
+pub use session_data::{init_room_data, InitRoomError, SessionData};
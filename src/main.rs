@@ -1,32 +1,64 @@
-use chrono::{TimeDelta, Utc};
-use client::LiveClient;
-use colored::{ColoredString, Colorize};
-use context::LiveContext;
-use depack::DepackedMessage;
-use message::data::GuardLevel;
-use message::interact::InteractType;
-use message::{LiveMessage, RawMessageDeserializeError};
-use session_data::init_room_data;
+use chrono::{DateTime, TimeDelta, Utc};
+use colored::{Color, ColoredString, Colorize};
 use simple_logger::SimpleLogger;
-use tungstenite::Message;
-use std::thread::sleep;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::Instant;
 use std::{env, time::Duration};
 
+use bilibili_live_danmaku_cli::backoff::Backoff;
+use bilibili_live_danmaku_cli::capture::{CaptureError, CaptureReader, CaptureWriter, FrameDirection};
+use bilibili_live_danmaku_cli::client::{decode_binary_frame, ClientError, LiveClient};
+use bilibili_live_danmaku_cli::dedup::MessageDeduplicator;
+use bilibili_live_danmaku_cli::depack::DepackedMessage;
+use bilibili_live_danmaku_cli::history::{fetch_danmaku_history, HistoryDanmaku};
+use bilibili_live_danmaku_cli::host_pool::HostPool;
+use bilibili_live_danmaku_cli::message::data::GuardLevel;
+use bilibili_live_danmaku_cli::message::interact::InteractType;
+use bilibili_live_danmaku_cli::message::LiveMessage;
+#[cfg(feature = "mock-server")]
+use bilibili_live_danmaku_cli::mock_server::MockServer;
+use bilibili_live_danmaku_cli::packet::http::WebsocketHost;
+use bilibili_live_danmaku_cli::packet::stream::PacketDecoder;
+use bilibili_live_danmaku_cli::session_data::{init_room_data, SessionData};
+
 mod config;
 mod context;
-mod depack;
-mod packet;
-mod message;
-mod session_data;
-mod client;
 
-use packet::{http::*, ws::*};
 use config::Config;
+use context::{CombinedSendGiftInfo, LiveContext};
+
+// Hosts failed within this duration are skipped when reconnecting
+const HOST_FAILURE_COOLDOWN_SEC: i64 = 60;
+
+// Messages seen within this duration are dropped when hot standby or backfill is enabled
+const DEDUP_WINDOW_SEC: i64 = 300;
+
+// Connections accepted and kept up this long are considered healthy even without a heartbeat response
+const HEALTHY_CONNECTION_DURATION: Duration = Duration::from_secs(30);
+
+// Hot standby connection without a host of its own checks for a free host this often
+const STANDBY_IDLE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Longest time a reconnect delay holds up a shutdown request
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
-use crate::client::ClientError;
-use crate::session_data::SessionData;
+// Label colors of rooms, assigned in the order rooms are specified
+const ROOM_LABEL_COLORS: [Color; 6] = [
+    Color::BrightCyan,
+    Color::BrightMagenta,
+    Color::BrightYellow,
+    Color::BrightBlue,
+    Color::BrightGreen,
+    Color::BrightRed,
+];
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     
     SimpleLogger::new().with_level(log::LevelFilter::Info).env().with_timestamp_format(
         time::macros::format_description!("[hour]:[minute]:[second]")
@@ -34,178 +66,746 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Get arguments
     let config = Config::from_args(env::args().collect());
 
-    let (session, hosts) = match init_room_data(config.room_id, config.uid, &config.sessdata) {
-        Ok(result) => result,
-        Err(e) => panic!("Failed to initialize room data: {}", e)
+    // Replay mode does not touch the network
+    if let Some(path) = &config.replay_path {
+        let shutdown = install_shutdown_handler()?;
+        start_replay(path, &config, &shutdown)?;
+        return Ok(());
+    }
+
+    // Mock server mode only serves local clients
+    #[cfg(feature = "mock-server")]
+    if let Some(addr) = &config.mock_server_addr {
+        let fixtures = match &config.mock_fixtures_path {
+            Some(path) => MockServer::load_fixtures(path)?,
+            None => vec![]
+        };
+        let server = MockServer::bind(addr, fixtures)?;
+        log::info!(target: "mock_server", "Mock server listening on {}", server.local_addr()?.to_string().bright_green());
+        server.serve()?;
+        return Ok(());
+    }
+    #[cfg(not(feature = "mock-server"))]
+    if config.mock_server_addr.is_some() {
+        return Err("Mock server is not available in this build, rebuild with `--features mock-server`".into());
+    }
+
+    let shutdown = install_shutdown_handler()?;
+
+    // Every room is listened in its own thread and reconnects independently
+    let failed_rooms = thread::scope(|scope| {
+        let handles: Vec<_> = config.room_ids.iter().enumerate().map(|(index, &room_id)| {
+            let label = room_label(index, room_id, &config);
+            let config = &config;
+            let shutdown = &shutdown;
+            (room_id, scope.spawn(move || run_room(room_id, &label, config, shutdown)))
+        }).collect();
+        handles.into_iter().map(|(room_id, handle)| {
+            match handle.join() {
+                Ok(Ok(())) => false,
+                Ok(Err(e)) => {
+                    log::error!(target: "init", "Stopped listening to room {}: {}", room_id, e);
+                    true
+                }
+                Err(_) => {
+                    log::error!(target: "init", "Listener of room {} panicked", room_id);
+                    true
+                }
+            }
+        }).filter(|failed| *failed).count()
+    });
+    if failed_rooms > 0 {
+        return Err(format!("{} of {} rooms stopped with error", failed_rooms, config.room_ids.len()).into());
+    }
+    Ok(())
+}
+
+// Raise the returned flag on SIGINT or SIGTERM, connections are closed and summaries printed before exiting
+fn install_shutdown_handler() -> Result<Arc<AtomicBool>, ctrlc::Error> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let flag = shutdown.clone();
+    ctrlc::set_handler(move || {
+        // A second signal exits immediately in case shutting down gets stuck
+        if flag.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        log::info!(target: "main", "Shutting down, press Ctrl-C again to exit immediately");
+    })?;
+    Ok(shutdown)
+}
+
+// Label printed before every line of a room, empty if only one room is monitored
+fn room_label(index: usize, room_id: u64, config: &Config) -> String {
+    if config.room_ids.len() <= 1 {
+        return String::new();
+    }
+    let color = ROOM_LABEL_COLORS[index % ROOM_LABEL_COLORS.len()];
+    format!("{} ", format!("[{}]", room_id).color(color))
+}
+
+// Capture files are separated by room when monitoring multiple rooms, e.g. live.cap becomes live.<room_id>.cap
+fn room_capture_path(path: &str, room_id: u64, config: &Config) -> String {
+    if config.room_ids.len() <= 1 {
+        return path.to_string();
+    }
+    insert_before_extension(path, &room_id.to_string())
+}
+
+// Each connection has its own capture file, frames of two connections can not be decoded as one stream.
+// The hot standby connection is recorded to e.g. live.standby.cap
+fn connection_capture_path(path: &str, index: usize) -> String {
+    match index {
+        0 => path.to_string(),
+        _ => insert_before_extension(path, "standby")
+    }
+}
+
+fn insert_before_extension(path: &str, tag: &str) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, tag, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, tag)
     };
+    path.with_file_name(file_name).to_string_lossy().to_string()
+}
 
-    // Get host uri
-    let host = hosts.get(0).expect("No available server in the list!").clone();
-    let host_url = format!("wss://{}:{}/sub", host.host, host.wss_port);
-    
+// Session data and hosts returned by room data request
+type RoomData = (SessionData, Vec<WebsocketHost>);
+
+// Events sent from connections of a room to its listener
+enum RoomEvent {
+    // Depacked messages tagged with the index of the connection
+    Messages(usize, Vec<DepackedMessage>),
+    // Danmaku fetched from history after reconnecting
+    Backfill(Vec<HistoryDanmaku>),
+}
+
+// Connection state shared by all connections of a room
+struct RoomState {
+    session: SessionData,
+    host_pool: HostPool,
+    // Host URL of every connection, hot standby connections are kept on different hosts
+    active_hosts: Vec<Option<String>>,
+    // Set once the primary connection has given up or shut down
+    primary_stopped: bool,
+}
+
+fn run_room(
+    room_id: u64,
+    label: &str,
+    config: &Config,
+    shutdown: &Arc<AtomicBool>
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (session, hosts) = match &config.server_url {
+        // Connect to the specified server directly as guest, without requesting room data
+        Some(_) => {
+            let session = SessionData {
+                room_id,
+                uid: config.uid.unwrap_or(0),
+                token: String::new(),
+            };
+            (session, vec![])
+        }
+        None => match init_room(room_id, label, config, shutdown)? {
+            Some(result) => result,
+            // Shutdown requested before the room is ready, nothing to flush
+            None => return Ok(())
+        }
+    };
+
+    if hosts.is_empty() && config.server_url.is_none() {
+        log::warn!(target: "init", "{}No available server in the list, will refresh host list", label);
+    }
+
+    let connection_count = if config.hot_standby { 2 } else { 1 };
+    let captures = (0..connection_count).map(|index| {
+        config.capture_path.as_ref().map(|path| {
+            let path = connection_capture_path(&room_capture_path(path, room_id, config), index);
+            log::info!(target: "init", "{}Recording received frames to {}", label, path.bright_green());
+            CaptureWriter::create(&path)
+                .map_err(|e| format!("Failed to create capture file {}: {}", path, e))
+        }).transpose()
+    }).collect::<Result<Vec<Option<CaptureWriter>>, _>>()?;
+    let display_room_id = session.room_id;
+    let state = Mutex::new(RoomState {
+        session,
+        host_pool: HostPool::new(&hosts, TimeDelta::seconds(HOST_FAILURE_COOLDOWN_SEC)),
+        active_hosts: vec![None; connection_count],
+        primary_stopped: false,
+    });
+
+    // Every connection reconnects independently and sends messages to the same listener
+    let (sender, events) = mpsc::channel();
+    thread::scope(|scope| {
+        let handles: Vec<_> = captures.into_iter().enumerate().map(|(index, capture)| {
+            let sender = sender.clone();
+            let state = &state;
+            scope.spawn(move || {
+                let result = run_connection(index, room_id, label, config, state, capture, sender, shutdown);
+                if index == 0 {
+                    state.lock().unwrap().primary_stopped = true;
+                }
+                result
+            })
+        }).collect();
+        drop(sender);
+        // Listener stops after every connection has given up
+        start_listening(events, display_room_id, label, config);
+        handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err("Connection thread panicked".into())))
+            .collect::<Result<Vec<()>, _>>()
+            .map(|_| ())
+    })
+}
+
+// Request room data until it succeeds, transient failures are retried with backoff.
+// Returns None if shutdown is requested while waiting.
+fn init_room(
+    room_id: u64,
+    label: &str,
+    config: &Config,
+    shutdown: &AtomicBool
+) -> Result<Option<RoomData>, Box<dyn Error + Send + Sync>> {
+    let mut backoff = Backoff::new(
+        Duration::from_millis(config.reconnect_delay_ms),
+        Duration::from_millis(config.reconnect_max_delay_ms)
+    );
     loop {
-        log::info!(target: "init", "Initializing connection to {} ...", host_url.bright_green());
+        let error = match init_room_data(room_id, config.uid, &config.sessdata, &config.api) {
+            Ok(result) => return Ok(Some(result)),
+            Err(e) if e.is_permanent() => return Err(e.into()),
+            Err(e) => e
+        };
+        let delay = backoff.next_delay();
+        if config.reconnect_max_retries.is_some_and(|max| backoff.failures() > max) {
+            return Err(format!("Failed to initialize room data after {} attempts: {}", backoff.failures(), error).into());
+        }
+        log::warn!(
+            target: "init",
+            "{}Failed to initialize room data, retry {} after {:.1} seconds (reason: {})",
+            label,
+            backoff.failures(),
+            delay.as_secs_f64(),
+            error
+        );
+        if !sleep_unless_shutdown(delay, shutdown) {
+            return Ok(None);
+        }
+    }
+}
 
-        if let Err(e) = start_listening(&session, &host_url, &config) {
-            log::warn!(target: "init", "Error occured in the connection: \n {}", e.to_string());
-        } else {
-            log::warn!(target: "init", "Connection closed by server");
+#[allow(clippy::too_many_arguments)]
+fn run_connection(
+    index: usize,
+    room_id: u64,
+    label: &str,
+    config: &Config,
+    state: &Mutex<RoomState>,
+    capture: Option<CaptureWriter>,
+    sender: Sender<RoomEvent>,
+    shutdown: &Arc<AtomicBool>
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut backoff = Backoff::new(
+        Duration::from_millis(config.reconnect_delay_ms),
+        Duration::from_millis(config.reconnect_max_delay_ms)
+    );
+    let mut connected_before = false;
+
+    loop {
+        if shutdown.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let host_url = match select_host(index, room_id, label, config, state) {
+            Some(url) => url,
+            // Hot standby waits for the primary connection to find hosts, without counting retries
+            None if index > 0 => {
+                if state.lock().unwrap().primary_stopped {
+                    log::info!(target: "init", "{}Hot standby connection stopped, no server was available", label);
+                    return Ok(());
+                }
+                log::debug!(target: "init", "{}No free server for hot standby connection, waiting...", label);
+                sleep_unless_shutdown(STANDBY_IDLE_INTERVAL, shutdown);
+                continue;
+            }
+            None => {
+                wait_reconnect(&mut backoff, label, config, shutdown, "No available server in the list")?;
+                continue;
+            }
+        };
+
+        log::info!(target: "init", "{}Initializing connection to {} ...", label, host_url.bright_green());
+
+        let session = state.lock().unwrap().session.clone();
+        let real_room_id = session.room_id;
+        let result = LiveClient::connect(&host_url, session, config.protover, capture.clone(), config.proxy.as_ref())
+            .and_then(|client| {
+                let mut client = client.with_shutdown(shutdown.clone());
+                let connected_at = Instant::now();
+                log::info!(target: "listener", "{}Connected to live room", label);
+                let backfill = config.backfill && connected_before;
+                connected_before = true;
+                let result = thread::scope(|scope| {
+                    // Danmaku sent while disconnected are fetched from history, in parallel so the new
+                    // connection keeps being read and sending heartbeats during the request
+                    if backfill {
+                        let sender = sender.clone();
+                        scope.spawn(move || backfill_history(real_room_id, label, config, &sender));
+                    }
+                    client.run_reader_with(
+                        |messages| sender.send(RoomEvent::Messages(index, messages)).is_ok(),
+                        TimeDelta::seconds(config.heartbeat_interval_sec as i64),
+                        TimeDelta::seconds(config.heartbeat_timeout_sec as i64)
+                    )
+                });
+                // Only reset backoff if the connection has proven healthy, a server that accepts and
+                // then drops every connection is still backed off and counted towards the retry limit
+                if client.certificate_accepted()
+                    && (client.heartbeat_acknowledged() || connected_at.elapsed() >= HEALTHY_CONNECTION_DURATION) {
+                    backoff.reset();
+                }
+                result
+            });
+
+        if shutdown.load(Ordering::Relaxed) {
+            state.lock().unwrap().active_hosts[index] = None;
+            if let Err(e) = result {
+                log::debug!(target: "listener", "{}Connection closed with error on shutdown: {}", label, e);
+            }
+            return Ok(());
+        }
+
+        let (reason, refresh) = {
+            let mut state = state.lock().unwrap();
+            state.active_hosts[index] = None;
+            match result {
+                Err(ClientError::AuthRejected(code)) if config.server_url.is_none() => {
+                    log::warn!(target: "init", "{}Certificate rejected by server (code {}), refreshing token...", label, code);
+                    (ClientError::AuthRejected(code).to_string(), true)
+                }
+                Err(e) => {
+                    state.host_pool.mark_failed(&host_url);
+                    (e.to_string(), false)
+                }
+                Ok(()) => {
+                    state.host_pool.mark_succeeded(&host_url);
+                    ("Connection closed by server".to_string(), false)
+                }
+            }
+        };
+        // Refreshed after releasing the state, other connections of the room are not held up
+        if refresh {
+            refresh_room_data(room_id, label, config, state);
         }
 
-        log::warn!(target: "init", "Reconnect after 5 seconds...");
+        wait_reconnect(&mut backoff, label, config, shutdown, &reason)?;
 
-        sleep(Duration::from_secs(5));
+        // The token may be stale after a long outage
+        if config.server_url.is_none() && backoff.failures().is_multiple_of(config.refresh_after_failures) {
+            log::warn!(target: "init", "{}Reconnect failed {} times, refreshing room data...", label, backoff.failures());
+            refresh_room_data(room_id, label, config, state);
+        }
     }
 }
 
-fn start_listening(
-    session: &SessionData,
-    host_url: &str,
+// Fetch recent danmaku and hand them to the listener, which drops the ones already shown
+fn backfill_history(room_id: u64, label: &str, config: &Config, sender: &Sender<RoomEvent>) {
+    match fetch_danmaku_history(&config.api, room_id) {
+        Ok(history) => {
+            // Danmaku older than the window may have been shown and forgotten by the deduplicator
+            let since = Utc::now() - TimeDelta::seconds(DEDUP_WINDOW_SEC);
+            let history: Vec<HistoryDanmaku> = history.into_iter()
+                .filter(|danmaku| danmaku.send_time >= since)
+                .collect();
+            log::debug!(target: "history", "{}Fetched {} recent danmaku for backfilling", label, history.len());
+            let _ = sender.send(RoomEvent::Backfill(history));
+        }
+        Err(e) => log::warn!(target: "history", "{}Failed to fetch danmaku history: {}", label, e)
+    }
+}
+
+// Select a host for the connection, avoiding hosts used by other connections of the room.
+// The hot standby shares the host of the primary connection if there is no other usable host.
+fn select_host(
+    index: usize,
+    room_id: u64,
+    label: &str,
     config: &Config,
-) -> Result<(), Box<ClientError>> {
+    state: &Mutex<RoomState>
+) -> Option<String> {
+    if let Some(server_url) = &config.server_url {
+        return Some(server_url.clone());
+    }
+    if let Some(url) = claim_host(index, &mut state.lock().unwrap()) {
+        return Some(url);
+    }
+    // Only the primary connection refreshes the host list, so failures are not cleared on every standby attempt
+    if index > 0 {
+        return None;
+    }
+    // Refresh host list when every host failed recently
+    log::warn!(target: "init", "{}All servers failed recently, refreshing host list...", label);
+    refresh_room_data(room_id, label, config, state);
+    // Retry the old hosts anyway if nothing new is available
+    let mut state = state.lock().unwrap();
+    state.host_pool.clear_failures();
+    claim_host(index, &mut state)
+}
+
+// Select a usable host and record it as the host of the connection
+fn claim_host(index: usize, state: &mut RoomState) -> Option<String> {
+    let others: Vec<String> = state.active_hosts.iter()
+        .enumerate()
+        .filter(|(other, _)| *other != index)
+        .filter_map(|(_, url)| url.clone())
+        .collect();
+    let host_url = state.host_pool.select_excluding(&others)?;
+    state.active_hosts[index] = Some(host_url.clone());
+    Some(host_url)
+}
+
+// Room data is requested without holding the state, which is only locked to store the result
+fn refresh_room_data(room_id: u64, label: &str, config: &Config, state: &Mutex<RoomState>) {
+    match init_room_data(room_id, config.uid, &config.sessdata, &config.api) {
+        Ok((new_session, hosts)) => {
+            let mut state = state.lock().unwrap();
+            state.session = new_session;
+            state.host_pool.replace_hosts(&hosts);
+        }
+        Err(e) => log::warn!(target: "init", "{}Failed to refresh room data: {}", label, e)
+    }
+}
+
+// Sleep for the next backoff delay, or give up if the retry limit is reached.
+// Returns early if shutdown is requested while sleeping.
+fn wait_reconnect(
+    backoff: &mut Backoff,
+    label: &str,
+    config: &Config,
+    shutdown: &AtomicBool,
+    reason: &str
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let delay = backoff.next_delay();
+    if config.reconnect_max_retries.is_some_and(|max| backoff.failures() > max) {
+        log::error!(target: "init", "{}Giving up after {} failed reconnect attempts: {}", label, backoff.failures() - 1, reason);
+        return Err(format!("Failed to reconnect after {} attempts", backoff.failures() - 1).into());
+    }
+    log::warn!(
+        target: "init",
+        "{}Reconnect attempt {} after {:.1} seconds (reason: {})",
+        label,
+        backoff.failures(),
+        delay.as_secs_f64(),
+        reason
+    );
+    sleep_unless_shutdown(delay, shutdown);
+    Ok(())
+}
 
-    let mut context = LiveContext::new();
+// Sleep in short steps so shutdown is not held up, returns false if shutdown is requested
+fn sleep_unless_shutdown(delay: Duration, shutdown: &AtomicBool) -> bool {
+    let deadline = Instant::now() + delay;
+    while !shutdown.load(Ordering::Relaxed) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        sleep(remaining.min(SHUTDOWN_POLL_INTERVAL));
+    }
+    false
+}
 
-    let mut client = LiveClient::connect(host_url, session.to_owned())?;
+fn start_listening(
+    events: Receiver<RoomEvent>,
+    room_id: u64,
+    label: &str,
+    config: &Config,
+) {
 
-    log::info!(target: "listener", "Connected to live room");
+    let mut context = LiveContext::new(label.to_string());
+    // Hot standby connections and backfilled history contain messages already received
+    let window = TimeDelta::seconds(DEDUP_WINDOW_SEC);
+    let mut deduplicator = match (config.hot_standby, config.backfill) {
+        (true, _) => Some(MessageDeduplicator::new(window)),
+        // History only contains danmaku, other messages need not be checked
+        (false, true) => Some(MessageDeduplicator::danmaku_only(window)),
+        (false, false) => None
+    };
 
-    let mut last_heartbeat = Utc::now();
-    // Main loop
+    // Main loop, wake up on new messages or when context events are due
     loop {
-        // Poll interval
-        sleep(Duration::from_millis(config.poll_interval_ms));
-        // Check heartbeat
-        if last_heartbeat
-            .checked_add_signed(TimeDelta::seconds(20))
-            .is_some_and(|time| Utc::now() > time) 
-        {
-            let packet = heartbeat_packet_binary();
-            if let Err(e) = client.send_message(Message::binary(packet)) {
-                log::warn!(
-                    target: "listener",
-                    "Failed to send heartbeat packet:\n {}",
-                    e
-                );
-            } else {
-                last_heartbeat = Utc::now();
-                log::debug!(
-                    target: "listener",
-                    "Heartbeat packet sent"
-                );
-            }
+        let event = match context.next_deadline() {
+            Some(deadline) => events.recv_timeout((deadline - Utc::now()).to_std().unwrap_or_default()),
+            None => events.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+        // Status line is cleared before any output of this wake-up and redrawn after
+        if config.status_line {
+            clear_status_line();
         }
         // Check events with context
-        for info in context.gift_list.get_expired() {
-            println!(
-                " * {} 投餵了 {} 個 {}",
-                get_colored_name(&info.user.username, info.user.guard_level),
-                info.gift_count.to_string().bright_yellow(),
-                info.gift_name.bright_magenta(),
-            );
-            context.gift_list.remove(&info);
+        let now = Utc::now();
+        process_context_events(&mut context, now);
+        // Process messages
+        match event {
+            Ok(RoomEvent::Messages(connection, messages)) => {
+                log::trace!(target: "listener", "Ready to process depacked messages...");
+                for message in messages {
+                    let message = match &mut deduplicator {
+                        Some(deduplicator) => deduplicator.filter(connection, message),
+                        None => message
+                    };
+                    process_depacked_message(message, config, &mut context, now);
+                }
+            }
+            Ok(RoomEvent::Backfill(history)) => {
+                for danmaku in history {
+                    if deduplicator.as_mut().is_some_and(|deduplicator| deduplicator.is_duplicate_identities(&danmaku.identities)) {
+                        continue;
+                    }
+                    process_live_message(LiveMessage::Danmaku(danmaku.info), config, &mut context, now);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                log::debug!(target: "listener", "{}All connections stopped", label);
+                flush_context(&mut context);
+                print_summary(&context);
+                return;
+            }
         }
-        for sc in context.superchat_list.get_should_show() {
-            let time_since_send = if sc.expired() {
-                sc.superchat_info.keep_time
-            } else {
-                (sc.next_show_time - sc.send_time).num_seconds() as u64
-            };
-            println!(
-                "[重放] {} <{}> ({})\n : {}",
-                "醒目留言".bright_cyan(),
-                get_colored_name(&sc.superchat_info.user.username, sc.superchat_info.user.guard_level),
-                format!(
-                    "${:.2} {}/{}s", 
-                    sc.superchat_info.price,
-                    time_since_send,
-                    sc.superchat_info.keep_time
-                ).bright_yellow(),
-                sc.superchat_info.message.bright_yellow(),
-            );
+        if config.status_line {
+            draw_status_line(room_id, &context);
         }
-        // Process messages
-        let messages = match client.recv_messages() {
-            Ok(x) => x,
-            Err(e) => match e {
-                ClientError::ConnectionClosed => {
-                    return Ok(());
+    }
+}
+
+fn start_replay(path: &str, config: &Config, shutdown: &AtomicBool) -> Result<(), CaptureError> {
+    let mut context = LiveContext::new(String::new());
+    let reader = CaptureReader::open(path)?;
+    let mut decoder = PacketDecoder::new();
+
+    log::info!(target: "replay", "Replaying capture file {} ...", path.bright_green());
+    let replay_room_id = config.room_ids.first().copied().unwrap_or(0);
+
+    // Context events are timed by capture time, so gift combos and superchats behave as recorded at any speed.
+    // Wall clock time and capture time of the first frame, used for pacing.
+    let mut start: Option<(DateTime<Utc>, i64)> = None;
+    for frame in reader {
+        if shutdown.load(Ordering::Relaxed) {
+            log::info!(target: "replay", "Replay interrupted");
+            break;
+        }
+        let frame = frame?;
+        if frame.direction != FrameDirection::Inbound {
+            continue;
+        }
+        let frame_time = capture_time(frame.timestamp_ms);
+        // Speed not greater than zero replays as fast as possible
+        if config.replay_speed > 0.0 {
+            let (start_time, start_timestamp) = *start.get_or_insert((Utc::now(), frame.timestamp_ms));
+            let wall_time = |time: DateTime<Utc>| {
+                let offset_ms = (time.timestamp_millis() - start_timestamp) as f64 / config.replay_speed;
+                start_time + TimeDelta::milliseconds(offset_ms as i64)
+            };
+            let target_time = wall_time(frame_time);
+            // Sleep until the frame is due, waking up for context events and shutdown requests in between
+            while let Ok(remaining) = (target_time - Utc::now()).to_std() {
+                if shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+                let remaining = match context.next_deadline() {
+                    Some(deadline) => remaining.min((wall_time(deadline) - Utc::now()).to_std().unwrap_or_default()),
+                    None => remaining
+                };
+                sleep(remaining.min(SHUTDOWN_POLL_INTERVAL));
+                if config.status_line {
+                    clear_status_line();
                 }
-                _ => {
-                    log::debug!(target: "listener", "Failed to poll messages");
-                    return Err(e.into())
+                // Capture time reached by now, never past the frame being waited for
+                let elapsed_ms = (Utc::now() - start_time).num_milliseconds() as f64 * config.replay_speed;
+                let now = capture_time(start_timestamp + elapsed_ms as i64).min(frame_time);
+                process_context_events(&mut context, now);
+                if config.status_line {
+                    draw_status_line(replay_room_id, &context);
                 }
             }
-        };
-        log::trace!(target: "listener", "Ready to process depacked messages...");
-        for message in messages {
-            process_depacked_message(message, config, &mut context);
         }
+        if config.status_line {
+            clear_status_line();
+        }
+        process_context_events(&mut context, frame_time);
+        for message in decode_binary_frame(&mut decoder, &frame.data) {
+            process_depacked_message(message, config, &mut context, frame_time);
+        }
+        if config.status_line {
+            draw_status_line(replay_room_id, &context);
+        }
+    }
+    if config.status_line {
+        clear_status_line();
+    }
+    // Pending gift combos are printed right away instead of waiting for them to expire
+    flush_context(&mut context);
+    if config.status_line {
+        draw_status_line(replay_room_id, &context);
+        println!();
+    }
+
+    log::info!(target: "replay", "Replay finished");
+    print_summary(&context);
+    Ok(())
+}
+
+// Convert capture timestamp in milliseconds to the time used by the context
+fn capture_time(timestamp_ms: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(timestamp_ms).unwrap_or_default()
+}
+
+fn process_context_events(context: &mut LiveContext, now: DateTime<Utc>) {
+    for info in context.gift_list.get_expired(now) {
+        print_combined_gift(&context.label, &info);
+    }
+    for sc in context.superchat_list.get_should_show(now) {
+        let time_since_send = if sc.expired(now) {
+            sc.superchat_info.keep_time
+        } else {
+            (sc.next_show_time - sc.send_time).num_seconds() as u64
+        };
+        println!(
+            "{}[重放] {} <{}> ({})\n : {}",
+            context.label,
+            "醒目留言".bright_cyan(),
+            get_colored_name(&sc.superchat_info.user.username, sc.superchat_info.user.guard_level),
+            format!(
+                "${:.2} {}/{}s", 
+                sc.superchat_info.price,
+                time_since_send,
+                sc.superchat_info.keep_time
+            ).bright_yellow(),
+            sc.superchat_info.message.bright_yellow(),
+        );
     }
 }
 
+// Print every pending gift combo without waiting for them to expire
+fn flush_context(context: &mut LiveContext) {
+    for info in context.gift_list.drain() {
+        print_combined_gift(&context.label, &info);
+    }
+}
+
+fn print_combined_gift(label: &str, info: &CombinedSendGiftInfo) {
+    println!(
+        "{} * {} 投餵了 {} 個 {}",
+        label,
+        get_colored_name(&info.user.username, info.user.guard_level),
+        info.gift_count.to_string().bright_yellow(),
+        info.gift_name.bright_magenta(),
+    );
+}
+
+// Print statistics of messages received in this run
+fn print_summary(context: &LiveContext) {
+    let summary = &context.summary;
+    let duration = (Utc::now() - summary.start_time).num_seconds();
+    println!(
+        "{} * {} ({:02}:{:02}:{:02})",
+        context.label,
+        "本次統計".bright_cyan(),
+        duration / 3600,
+        duration % 3600 / 60,
+        duration % 60
+    );
+    println!(
+        "{}   彈幕 {} 條，發言觀眾 {} 人",
+        context.label,
+        summary.danmaku_count.to_string().bright_yellow(),
+        summary.speakers.len().to_string().bright_yellow()
+    );
+    // Gifts with larger count first
+    let mut gifts: Vec<(&String, &u64)> = summary.gifts.iter().collect();
+    gifts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    let gift_text = match gifts.is_empty() {
+        true => "無".to_string(),
+        false => gifts.iter()
+            .map(|(name, count)| format!("{} x {}", name.bright_magenta(), count.to_string().bright_yellow()))
+            .collect::<Vec<String>>()
+            .join("，")
+    };
+    println!("{}   禮物 {}", context.label, gift_text);
+    println!(
+        "{}   醒目留言 {} 條，共 {}",
+        context.label,
+        summary.superchat_count.to_string().bright_yellow(),
+        format!("${:.2}", summary.superchat_price).bright_yellow()
+    );
+    let guard_text = match summary.guards.is_empty() {
+        true => "無".to_string(),
+        false => summary.guards.iter()
+            .map(|info| format!(
+                "{} {} ({} 個月)",
+                get_colored_name(&info.user.username, Some(info.guard_level)),
+                get_colored_name(info.guard_level.name(), Some(info.guard_level)),
+                info.count
+            ))
+            .collect::<Vec<String>>()
+            .join("，")
+    };
+    println!("{}   新上艦 {}", context.label, guard_text);
+}
+
 fn process_depacked_message(
     message: DepackedMessage, 
     config: &Config, 
-    context: &mut LiveContext
+    context: &mut LiveContext,
+    now: DateTime<Utc>
 ) {
     // Display certificate resp and heartbeat resp ony in debug
-    let messages = match message {
-        DepackedMessage::CertificateResp => {
-            log::debug!(target: "msg_process", "Received certificate response");
-            return;
+    match &message {
+        DepackedMessage::CertificateResp(resp) => {
+            log::debug!(target: "msg_process", "Received certificate response (code {})", resp.code);
         },
         DepackedMessage::HeartbeatResp(count) => {
             log::debug!(target: "msg_process", "Received heartbeat response ({})", count);
-            return;
         },
-        DepackedMessage::LiveMessages(messages) => messages
+        DepackedMessage::LiveMessages(_) => {}
     };
-    for raw_message in messages {
-        let live_message = match LiveMessage::try_from(raw_message) {
-            Ok(x) => x,
-            Err(RawMessageDeserializeError::NotSupported(cmd)) => {
-                log::debug!(target: "msg_process", "Ignored unsupported command type {:#?}", cmd);
-                continue;
-            },
-            Err(RawMessageDeserializeError::DeserializeError(message)) => {
-                log::warn!(target: "msg_process", "Failed to deserialize raw message into live message");
-                log::warn!(target: "msg_process", "Live message: {}", message);
-                continue;
-            }
-        };
-        process_live_message(live_message, config, context);
+    for live_message in message.into_live_messages() {
+        process_live_message(live_message, config, context, now);
     }
 }
 
+// Gift combos and superchats are timed from `now`, which is the capture time of the frame when replaying
 fn process_live_message(
     message: LiveMessage, 
     config: &Config, 
-    context: &mut LiveContext
+    context: &mut LiveContext,
+    now: DateTime<Utc>
 ) {
     log::debug!(target: "msg_process", "Processing Live Message:\n{:#?}", message);
+    context.summary.record(&message);
     match message {
         LiveMessage::LiveStart(_) => {
-            println!(" * {}", "直播開始了".bright_green());
+            println!("{} * {}", context.label, "直播開始了".bright_green());
         }
         LiveMessage::LiveStop(_) => {
-            println!(" * {}", "直播結束了".bright_red());
+            println!("{} * {}", context.label, "直播結束了".bright_red());
         }
         LiveMessage::Welcome(info) => {
             let username = match info.is_admin {
                 true => info.username.bright_red(),
                 false => info.username.bright_green(),
             };
-            println!(" * {} 進入了直播間", username);
+            println!("{} * {} 進入了直播間", context.label, username);
         }
         LiveMessage::WelcomeGuard(info) => {
-            println!(" * {} 進入了直播間", get_colored_name(&info.username, info.guard_level));
+            println!("{} * {} 進入了直播間", context.label, get_colored_name(&info.username, info.guard_level));
         }
         LiveMessage::Warning(info) => {
-            println!(" * {} {}", "超管警告".bright_red(), info.message.bright_red())
+            println!("{} * {} {}", context.label, "超管警告".bright_red(), info.message.bright_red())
         }
         LiveMessage::LiveCutOff(info) => {
-            println!(" * {} {}", "直播被切斷".bright_red(), info.message.bright_red())
+            println!("{} * {} {}", context.label, "直播被切斷".bright_red(), info.message.bright_red())
         }
         LiveMessage::Danmaku(info) => {
             let username = match (info.is_admin, info.user.guard_level) {
@@ -218,8 +818,14 @@ fn process_live_message(
                 }
                 None => "".to_string()
             };
+            let backfill_text = match info.backfilled {
+                true => format!("{} ", "[補發]".bright_black()),
+                false => "".to_string()
+            };
             println!(
-                "{}{}\n : {}",
+                "{}{}{}{}\n : {}",
+                context.label,
+                backfill_text,
                 badge_text,
                 username,
                 info.text
@@ -230,11 +836,13 @@ fn process_live_message(
                 context.gift_list.append_gift(
                     info, 
                     TimeDelta::milliseconds(config.gift_combo_interval_ms as i64), 
-                    false
+                    false,
+                    now
                 );
             } else {
                 println!(
-                    " * {} 投餵了 {} 個 {}",
+                    "{} * {} 投餵了 {} 個 {}",
+                    context.label,
                     get_colored_name(&info.user.username, info.user.guard_level),
                     info.count.to_string().bright_yellow(),
                     info.gift_name.bright_magenta(),
@@ -243,7 +851,8 @@ fn process_live_message(
         }
         LiveMessage::SuperChat(info) => {
             println!(
-                "{} <{}> ({})\n : {}",
+                "{}{} <{}> ({})\n : {}",
+                context.label,
                 "醒目留言".bright_cyan(),
                 get_colored_name(&info.user.username, info.user.guard_level),
                 format!("${:.2} {}s", info.price, info.keep_time).bright_yellow(),
@@ -252,29 +861,34 @@ fn process_live_message(
             if config.repeat_superchat {
                 context.superchat_list.append_superchat(
                     info, 
-                    TimeDelta::seconds(config.repeat_superchat_interval_sec as i64)
+                    TimeDelta::seconds(config.repeat_superchat_interval_sec as i64),
+                    now
                 );
             }
         }
         LiveMessage::Interact(info) => {
             let colored_name = get_colored_name(&info.user.username, info.user.guard_level);
             match info.interact_type {
-                InteractType::Enter => println!(" * {} 進入了直播間", colored_name),
-                InteractType::Follow => println!(" * {} 關注了你", colored_name),
-                InteractType::Share => println!(" * {} 分享了直播間", colored_name),
-                InteractType::SpecialFollow => println!(" * {} 特別關注了你", colored_name),
-                InteractType::MutualFollow => println!(" * {} 互關了你", colored_name),
+                InteractType::Enter => println!("{} * {} 進入了直播間", context.label, colored_name),
+                InteractType::Follow => println!("{} * {} 關注了你", context.label, colored_name),
+                InteractType::Share => println!("{} * {} 分享了直播間", context.label, colored_name),
+                InteractType::SpecialFollow => println!("{} * {} 特別關注了你", context.label, colored_name),
+                InteractType::MutualFollow => println!("{} * {} 互關了你", context.label, colored_name),
             }
         }
         LiveMessage::GuardBuy(info) => {
             let guard_name = info.guard_level.name();
             println!(
-                " * {} 成為了 {} ({} 個月)",
+                "{} * {} 成為了 {} ({} 個月)",
+                context.label,
                 get_colored_name(&info.user.username, Some(info.guard_level)),
                 get_colored_name(guard_name, Some(info.guard_level)),
                 info.count.to_string().bright_yellow()
             );
         }
+        LiveMessage::Popularity(info) => {
+            context.popularity = Some(info.count);
+        }
         #[allow(unreachable_patterns)]
         other => {
             log::debug!(target: "msg_process", "Ignored message that does not need to be displayed: {:#?}", other)
@@ -282,6 +896,26 @@ fn process_live_message(
     }
 }
 
+// Clear the status line drawn at the bottom, output of this tick will take its place
+fn clear_status_line() {
+    print!("\r\x1b[2K");
+}
+
+// Draw the status line without newline so it can be refreshed in place
+fn draw_status_line(room_id: u64, context: &LiveContext) {
+    let popularity = match context.popularity {
+        Some(count) => count.to_string(),
+        None => "-".to_string()
+    };
+    print!(
+        "[{}] {} {}",
+        room_id.to_string().bright_green(),
+        "人氣".bright_cyan(),
+        popularity.bright_yellow()
+    );
+    let _ = std::io::stdout().flush();
+}
+
 // Get colored name of a guard
 fn get_colored_name(name: &str, guard_level: Option<GuardLevel>) -> ColoredString {
     match guard_level {
@@ -10,6 +10,8 @@ pub struct DanmakuInfo {
     pub is_admin: bool,
     pub is_vip: bool,
     pub text: String,
+    // Fetched from history after reconnecting instead of pushed by the server
+    pub backfilled: bool,
 }
 
 impl DanmakuInfo {
@@ -33,6 +35,7 @@ impl DanmakuInfo {
             text: text.to_string(),
             is_admin,
             is_vip,
+            backfilled: false,
         };
         Some(danmaku_info)
     }
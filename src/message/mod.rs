@@ -3,6 +3,7 @@ pub mod gift;
 pub mod guard;
 pub mod interact;
 pub mod live;
+pub mod popularity;
 pub mod super_chat;
 pub mod warning;
 pub mod welcome;
@@ -19,6 +20,7 @@ use gift::SendGiftInfo;
 use guard::GuardBuyInfo;
 use interact::InteractInfo;
 use live::{LiveStartInfo, LiveStopInfo, LiveCutOffInfo};
+use popularity::PopularityInfo;
 use super_chat::SuperChatInfo;
 use warning::WarningInfo;
 use welcome::{WelcomeInfo, WelcomeGuardInfo};
@@ -80,6 +82,7 @@ pub enum LiveMessage {
     Interact        (InteractInfo),
     GuardBuy        (GuardBuyInfo),
     GiftTop         (GiftTopInfo),
+    Popularity      (PopularityInfo),
 }
 
 impl TryFrom<RawLiveMessage> for LiveMessage {
@@ -0,0 +1,18 @@
+use chrono::{DateTime, Utc};
+
+/// Popularity count carried by heartbeat responses, not a command message
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct PopularityInfo {
+    pub count: u64,
+    pub receive_time: DateTime<Utc>,
+}
+
+impl PopularityInfo {
+    pub fn new(count: u64) -> Self {
+        PopularityInfo {
+            count,
+            receive_time: Utc::now()
+        }
+    }
+}
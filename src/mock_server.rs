@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use derive_more::Display;
+use serde_json::Value;
+use tungstenite::{Message, WebSocket};
+
+use crate::packet::encoder::PacketEncoder;
+use crate::packet::ws::{CertificatePacketBody, Packet, PacketConvertError, PacketType, Protocol};
+
+#[derive(Debug, Display)]
+pub enum MockServerError {
+    IOError(std::io::Error),
+    TungsteniteError(tungstenite::Error),
+    FixtureError(serde_json::Error),
+    HandshakeFailed,
+    UnexpectedPacket,
+}
+
+impl std::error::Error for MockServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self {
+            Self::IOError(e) => Some(e),
+            Self::TungsteniteError(e) => Some(e),
+            Self::FixtureError(e) => Some(e),
+            Self::HandshakeFailed => None,
+            Self::UnexpectedPacket => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MockServerError {
+    fn from(err: std::io::Error) -> Self {
+        MockServerError::IOError(err)
+    }
+}
+
+impl From<tungstenite::Error> for MockServerError {
+    fn from(err: tungstenite::Error) -> Self {
+        MockServerError::TungsteniteError(err)
+    }
+}
+
+/// Local danmaku server speaking the live protocol, for testing clients without bilibili.com
+pub struct MockServer {
+    listener: TcpListener,
+    // Raw command messages pushed to every client after certificate
+    fixtures: Vec<Value>,
+    // Drop the socket without a close frame right after pushing fixtures
+    disconnect_after_fixtures: bool,
+    // Certificate response code, anything but 0 rejects the client
+    certificate_code: i32,
+}
+
+impl MockServer {
+    pub fn bind(addr: &str, fixtures: Vec<Value>) -> Result<Self, MockServerError> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(MockServer { listener, fixtures, disconnect_after_fixtures: false, certificate_code: 0 })
+    }
+
+    /// Drop every connection right after pushing fixtures, like a server going away, to exercise reconnecting
+    pub fn disconnect_after_fixtures(mut self) -> Self {
+        self.disconnect_after_fixtures = true;
+        self
+    }
+
+    /// Answer every certificate with the non-zero code and close the connection, like a server refusing the token
+    pub fn reject_certificate(mut self, code: i32) -> Self {
+        self.certificate_code = code;
+        self
+    }
+
+    /// Load fixtures from a JSON file containing an array of raw command messages
+    pub fn load_fixtures(path: &str) -> Result<Vec<Value>, MockServerError> {
+        let reader = BufReader::new(File::open(path)?);
+        serde_json::from_reader(reader).map_err(MockServerError::FixtureError)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, MockServerError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept connections forever, each connection is served in its own thread
+    pub fn serve(self) -> Result<(), MockServerError> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let fixtures = self.fixtures.clone();
+            let disconnect = self.disconnect_after_fixtures;
+            let certificate_code = self.certificate_code;
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = serve_connection(stream, &fixtures, disconnect, certificate_code) {
+                    log::warn!(target: "mock_server", "Connection {:?} closed with error: {}", peer, e);
+                } else {
+                    log::info!(target: "mock_server", "Connection {:?} closed", peer);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn send_binary(socket: &mut WebSocket<TcpStream>, binary: Result<Vec<u8>, PacketConvertError>) -> Result<(), MockServerError> {
+    let binary = binary.map_err(|_| MockServerError::UnexpectedPacket)?;
+    socket.send(Message::binary(binary))?;
+    Ok(())
+}
+
+fn serve_connection(stream: TcpStream, fixtures: &[Value], disconnect: bool, certificate_code: i32) -> Result<(), MockServerError> {
+    let mut socket = tungstenite::accept(stream).map_err(|_| MockServerError::HandshakeFailed)?;
+
+    // The first packet must be the certificate packet
+    let certificate = match socket.read()? {
+        Message::Binary(data) => Packet::from_binary(&data).map_err(|_| MockServerError::UnexpectedPacket)?,
+        _ => return Err(MockServerError::UnexpectedPacket)
+    };
+    if certificate.header.packet_type != PacketType::Certificate as u32 {
+        return Err(MockServerError::UnexpectedPacket);
+    }
+    let certificate: CertificatePacketBody = serde_json::from_slice(&certificate.body)
+        .map_err(MockServerError::FixtureError)?;
+    log::info!(target: "mock_server", "Certificate received for room {} (uid {})", certificate.roomid, certificate.uid);
+    // Control frames carry payload too, clients must not mistake it for packet data
+    socket.send(Message::Ping(b"mock".to_vec().into()))?;
+    let mut encoder = PacketEncoder::new();
+    let certificate_resp = format!("{{\"code\":{}}}", certificate_code);
+    send_binary(&mut socket, encoder.encode(Protocol::Special, PacketType::CertificateResp, certificate_resp.as_bytes()))?;
+    if certificate_code != 0 {
+        // Wait for the client to answer the close frame, dropping the socket earlier may reset the response away
+        socket.close(None)?;
+        loop {
+            match socket.read() {
+                Ok(_) => continue,
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Err(e) => return Err(e.into())
+            }
+        }
+    }
+
+    if !fixtures.is_empty() {
+        let commands = fixtures.iter()
+            .map(serde_json::to_vec)
+            .collect::<Result<Vec<Vec<u8>>, _>>()
+            .map_err(MockServerError::FixtureError)?;
+        send_binary(&mut socket, encoder.encode_commands(Protocol::CommandBrotli, &commands))?;
+    }
+    if disconnect {
+        socket.get_ref().shutdown(Shutdown::Both)?;
+        return Ok(());
+    }
+
+    // Answer heartbeats with an increasing popularity count until the client leaves
+    let mut popularity: u32 = 0;
+    loop {
+        let data = match socket.read() {
+            Ok(Message::Binary(data)) => data,
+            Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Ok(_) => continue,
+            Err(e) => return Err(e.into())
+        };
+        let packet = Packet::from_binary(&data).map_err(|_| MockServerError::UnexpectedPacket)?;
+        if packet.header.packet_type == PacketType::Heartbeat as u32 {
+            popularity += 1;
+            send_binary(&mut socket, encoder.encode(Protocol::Special, PacketType::HeartbeatResp, &popularity.to_be_bytes()))?;
+        }
+    }
+}
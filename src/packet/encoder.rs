@@ -0,0 +1,187 @@
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+
+use super::ws::{CertificatePacketBody, Packet, PacketConvertError, PacketType, Protocol, Protover};
+
+// Body of heartbeat packets, kept byte-identical to the frame sent before the encoder existed
+const HEARTBEAT_BODY: &[u8] = b"[Object object]";
+
+/// Packet encoder of a connection, every encoded packet takes the next sequence number
+pub struct PacketEncoder {
+    sequence: u32,
+}
+
+impl Default for PacketEncoder {
+    fn default() -> Self {
+        PacketEncoder { sequence: 1 }
+    }
+}
+
+impl PacketEncoder {
+    pub fn new() -> Self {
+        PacketEncoder::default()
+    }
+
+    fn next_sequence(&mut self) -> u32 {
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+        sequence
+    }
+
+    /// Encode a packet, the body is compressed if the protocol is a compressed one
+    pub fn encode(&mut self, protocol: Protocol, packet_type: PacketType, body: &[u8]) -> Result<Vec<u8>, PacketConvertError> {
+        let body = match protocol {
+            Protocol::CommandBrotli => {
+                let mut compressed = vec![];
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+                    writer.write_all(body).map_err(PacketConvertError::CompressError)?;
+                }
+                compressed
+            }
+            Protocol::CommandZlib => {
+                let mut writer = ZlibEncoder::new(vec![], flate2::Compression::default());
+                writer.write_all(body).map_err(PacketConvertError::CompressError)?;
+                writer.finish().map_err(PacketConvertError::CompressError)?
+            }
+            Protocol::Command | Protocol::Special => body.to_vec()
+        };
+        let sequence = self.next_sequence();
+        Packet::create(protocol, packet_type, sequence, body)
+            .to_binary()
+            .map_err(PacketConvertError::BinCodeError)
+    }
+
+    /// Encode command bodies as inner packets, then pack them into one packet of the compressed protocol.
+    /// Uncompressed packets carry the command itself as body, so only a single command is accepted for them.
+    pub fn encode_commands(&mut self, protocol: Protocol, commands: &[Vec<u8>]) -> Result<Vec<u8>, PacketConvertError> {
+        if matches!(protocol, Protocol::Command | Protocol::Special) {
+            return match commands {
+                [command] => self.encode(protocol, PacketType::Command, command),
+                _ => Err(PacketConvertError::MultipleUncompressedCommands)
+            };
+        }
+        let mut inner = vec![];
+        for command in commands {
+            // Inner packets do not take sequence numbers of the connection
+            let packet = Packet::create(Protocol::Command, PacketType::Command, 0, command.clone());
+            inner.extend(packet.to_binary().map_err(PacketConvertError::BinCodeError)?);
+        }
+        self.encode(protocol, PacketType::Command, &inner)
+    }
+
+    pub fn certificate(&mut self, uid: u64, room_id: u64, token: &str, protover: Protover) -> Result<Vec<u8>, PacketConvertError> {
+        let cert_body = CertificatePacketBody {
+            uid,
+            roomid: room_id,
+            key: token.to_string(),
+            // Server will send command packets compressed with this protover
+            protover: protover as u8
+        };
+        let cert_body = serde_json::ser::to_vec(&cert_body).map_err(|_| PacketConvertError::BodySerializeError)?;
+        self.encode(Protocol::Special, PacketType::Certificate, &cert_body)
+    }
+
+    pub fn heartbeat(&mut self) -> Result<Vec<u8>, PacketConvertError> {
+        self.encode(Protocol::Special, PacketType::Heartbeat, HEARTBEAT_BODY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::depack::{depack_packets, DepackedMessage};
+
+    // Heartbeat frame that used to be hardcoded, sent with sequence 1
+    const LEGACY_HEARTBEAT: [u8; 31] = [
+        0, 0, 0, 31,
+        0, 16,
+        0, 1,
+        0, 0, 0, 2,
+        0, 0, 0, 1,
+        91, 79, 98, 106, 101, 99, 116, 32, 111, 98, 106, 101, 99, 116, 93
+    ];
+
+    fn sequence(binary: &[u8]) -> u32 {
+        Packet::from_binary(binary).unwrap().header.sequence
+    }
+
+    #[test]
+    fn sequence_increases_per_encoder() {
+        let mut encoder = PacketEncoder::new();
+        let sequences: Vec<u32> = vec![
+            sequence(&encoder.certificate(0, 1, "", Protover::Brotli).unwrap()),
+            sequence(&encoder.heartbeat().unwrap()),
+            sequence(&encoder.encode(Protocol::Command, PacketType::Command, b"{}").unwrap()),
+        ];
+        assert_eq!(sequences, [1, 2, 3]);
+
+        // Another connection starts over
+        assert_eq!(sequence(&PacketEncoder::new().heartbeat().unwrap()), 1);
+    }
+
+    #[test]
+    fn heartbeat_matches_legacy_frame() {
+        let mut encoder = PacketEncoder::new();
+        assert_eq!(encoder.heartbeat().unwrap(), LEGACY_HEARTBEAT);
+
+        // Only the sequence number differs afterwards
+        let heartbeat = encoder.heartbeat().unwrap();
+        assert_eq!(heartbeat[..12], LEGACY_HEARTBEAT[..12]);
+        assert_eq!(heartbeat[12..16], 2u32.to_be_bytes());
+        assert_eq!(heartbeat[16..], LEGACY_HEARTBEAT[16..]);
+    }
+
+    // Parse the encoded packet back and return commands inside
+    fn round_trip(binary: &[u8], protocol: Protocol) -> Vec<String> {
+        let packet = Packet::from_binary(binary).unwrap();
+        assert_eq!(packet.header.protocol, protocol as u16);
+        assert_eq!(packet.header.total_size as usize, binary.len());
+        match depack_packets(packet.header, &packet.body).unwrap() {
+            DepackedMessage::LiveMessages(messages) => messages.into_iter().map(|message| message.cmd).collect(),
+            _ => panic!("expected live messages"),
+        }
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = vec![
+            br#"{"cmd":"LIVE","roomid":4793604}"#.to_vec(),
+            br#"{"cmd":"WARNING","msg":"warning"}"#.to_vec(),
+        ];
+        let mut encoder = PacketEncoder::new();
+
+        // Uncompressed packets carry a single command
+        let binary = encoder.encode_commands(Protocol::Command, &commands[..1]).unwrap();
+        assert_eq!(round_trip(&binary, Protocol::Command), ["LIVE"]);
+        assert!(matches!(
+            encoder.encode_commands(Protocol::Command, &commands),
+            Err(PacketConvertError::MultipleUncompressedCommands)
+        ));
+        assert!(matches!(
+            encoder.encode_commands(Protocol::Special, &[]),
+            Err(PacketConvertError::MultipleUncompressedCommands)
+        ));
+
+        let binary = encoder.encode_commands(Protocol::CommandZlib, &commands).unwrap();
+        assert_eq!(round_trip(&binary, Protocol::CommandZlib), ["LIVE", "WARNING"]);
+
+        let binary = encoder.encode_commands(Protocol::CommandBrotli, &commands).unwrap();
+        assert_eq!(round_trip(&binary, Protocol::CommandBrotli), ["LIVE", "WARNING"]);
+    }
+
+    #[test]
+    fn certificate_body_round_trip() {
+        let binary = PacketEncoder::new().certificate(1939036, 4793604, "token", Protover::Zlib).unwrap();
+        let packet = Packet::from_binary(&binary).unwrap();
+        assert_eq!(packet.header.protocol, Protocol::Special as u16);
+        assert_eq!(packet.header.packet_type, PacketType::Certificate as u32);
+
+        let body: CertificatePacketBody = serde_json::from_slice(&packet.body).unwrap();
+        assert_eq!(body.uid, 1939036);
+        assert_eq!(body.roomid, 4793604);
+        assert_eq!(body.key, "token");
+        assert_eq!(body.protover, Protover::Zlib as u8);
+    }
+}
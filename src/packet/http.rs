@@ -1,10 +1,13 @@
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
+// Error responses may come without message or data
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct HttpAPIResponse<T> {
     code: isize,
+    #[serde(default)]
     message: String,
+    #[serde(default)]
     data: T
 }
 
@@ -13,6 +16,9 @@ impl<T> HttpAPIResponse<T> {
     pub fn code(&self) -> isize {
         self.code
     }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
     pub fn ok(&self) -> bool {
         self.code == 0
     }
@@ -35,6 +41,11 @@ pub struct RoomInitData {
     pub short_id: u64,
     pub uid: u64,
     pub live_status: LiveStatus,
+    // Banned rooms are locked until the time in seconds
+    #[serde(default)]
+    pub is_locked: bool,
+    #[serde(default)]
+    pub lock_till: i64,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
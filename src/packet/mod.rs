@@ -1,2 +1,4 @@
+pub mod encoder;
 pub mod http;
+pub mod stream;
 pub mod ws;
\ No newline at end of file
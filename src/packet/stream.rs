@@ -0,0 +1,87 @@
+use super::ws::{Packet, PacketConvertError};
+
+/// Incremental packet decoder, buffers partial data and yields every complete packet.
+///
+/// A WebSocket frame may carry several concatenated packets, or only a part of one.
+#[derive(Default)]
+pub struct PacketDecoder {
+    buffer: Vec<u8>,
+}
+
+impl PacketDecoder {
+    pub fn new() -> Self {
+        PacketDecoder::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Number of bytes waiting for the rest of a packet
+    pub fn pending_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Return next complete packet, or `None` if more data is needed.
+    /// Buffered data is discarded if the header is invalid since the packet boundary is lost.
+    pub fn next_packet(&mut self) -> Result<Option<Packet>, PacketConvertError> {
+        if self.buffer.len() < 16 {
+            return Ok(None);
+        }
+        match Packet::from_binary(&self.buffer) {
+            Ok(packet) => {
+                self.buffer.drain(..packet.header.total_size as usize);
+                Ok(Some(packet))
+            }
+            Err(PacketConvertError::PacketLengthError) => Ok(None),
+            Err(e) => {
+                self.buffer.clear();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Iterator for PacketDecoder {
+    type Item = Result<Packet, PacketConvertError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::ws::{PacketType, Protocol, MAX_PACKET_SIZE};
+
+    fn command(body: &[u8]) -> Vec<u8> {
+        Packet::create(Protocol::Command, PacketType::Command, 0, body.to_vec()).to_binary().unwrap()
+    }
+
+    #[test]
+    fn fragmented_and_concatenated_packets() {
+        let data = [command(b"{\"cmd\":\"A\"}"), command(b"{\"cmd\":\"B\"}")].concat();
+        let mut decoder = PacketDecoder::new();
+        decoder.push(&data[..20]);
+        assert!(decoder.next_packet().unwrap().is_none());
+        decoder.push(&data[20..]);
+        let bodies: Vec<Vec<u8>> = decoder.by_ref().map(|packet| packet.unwrap().body).collect();
+        assert_eq!(bodies, [b"{\"cmd\":\"A\"}".to_vec(), b"{\"cmd\":\"B\"}".to_vec()]);
+        assert_eq!(decoder.pending_len(), 0);
+    }
+
+    #[test]
+    fn oversized_header_clears_buffer() {
+        let mut packet = Packet::create(Protocol::Command, PacketType::Command, 0, b"{}".to_vec());
+        packet.header.total_size = MAX_PACKET_SIZE as u32 + 1;
+        let mut decoder = PacketDecoder::new();
+        decoder.push(&packet.to_binary().unwrap());
+        assert!(matches!(decoder.next_packet(), Err(PacketConvertError::HeaderSizeError)));
+        assert_eq!(decoder.pending_len(), 0);
+
+        // Following packets are decoded normally
+        decoder.push(&command(b"{}"));
+        assert_eq!(decoder.next_packet().unwrap().unwrap().body, b"{}");
+    }
+}
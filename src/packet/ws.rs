@@ -3,6 +3,9 @@ use derive_more::Display;
 use bincode::Options;
 use serde::{Deserialize, Serialize};
 
+// Packets are at most a few hundred KiB, larger sizes only come from corrupt headers
+pub const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
 #[repr(u16)]
 pub enum Protocol {
     Command         = 0,
@@ -47,7 +50,8 @@ impl TryFrom<u32> for PacketType {
     }
 }
 
-#[allow(unused)]
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum Protover {
     Normal = 1,
@@ -55,6 +59,18 @@ pub enum Protover {
     Brotli = 3,
 }
 
+impl TryFrom<&str> for Protover {
+    type Error = ();
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "normal" => Ok(Protover::Normal),
+            "zlib" => Ok(Protover::Zlib),
+            "brotli" => Ok(Protover::Brotli),
+            _ => Err(())
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PacketHeader {
     pub total_size: u32,
@@ -73,8 +89,7 @@ pub struct CertificatePacketBody {
     pub protover: u8,
 }
 
-// It's fine to ignore the certificate response body so it is marked allow(unused)
-#[allow(unused)]
+// Note: Certificate response body is in JSON format, non-zero code means rejected
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CertificateRespBody {
     pub code: i32
@@ -87,13 +102,13 @@ pub struct Packet {
 }
 
 impl Packet {
-    pub fn create(protocol: Protocol, packet_type: PacketType, body: Vec<u8>) -> Self {
+    pub fn create(protocol: Protocol, packet_type: PacketType, sequence: u32, body: Vec<u8>) -> Self {
         let header = PacketHeader {
             total_size: (body.len() + 16) as u32,
             head_size: 16,
             protocol: protocol as u16,
             packet_type: packet_type as u32,
-            sequence: 1
+            sequence
         };
         Packet { header, body }
     }
@@ -114,31 +129,27 @@ impl Packet {
         .with_fixint_encoding()
         .with_big_endian();
         let header: PacketHeader = config.deserialize(&data[..16]).map_err(|e| PacketConvertError::BinCodeError(e))?;
+        let head_size = header.head_size as usize;
         let total_size = header.total_size as usize;
+        if head_size < 16 || head_size > total_size || total_size > MAX_PACKET_SIZE {
+            return Err(PacketConvertError::HeaderSizeError);
+        }
         if data.len() < total_size {
             return Err(PacketConvertError::PacketLengthError);
         }
-        let body = data[16..total_size as usize].to_vec();
+        let body = data[head_size..total_size].to_vec();
         Ok(Packet{ header, body })
     }
-
-    pub fn new_certificate_packet(uid: u64, room_id: u64, token: &str) -> Result<Packet, PacketConvertError> {
-        let cert_body = CertificatePacketBody {
-            uid,
-            roomid: room_id,
-            key: token.to_string(),
-            // Protover is set to Brotli, not tested on Zlib or Normal
-            protover: Protover::Brotli as u8
-        };
-        let cert_body = serde_json::ser::to_string(&cert_body).map_err(|_| PacketConvertError::BodySerializeError)?;
-        Ok(Packet::create(Protocol::Special, PacketType::Certificate, cert_body.as_bytes().to_vec()))
-    }
 }
 
 #[derive(Debug, Display)]
 pub enum PacketConvertError {
     PacketLengthError,
+    HeaderSizeError,
     BodySerializeError,
+    // Uncompressed packet body is a single command, several commands need a compressed protocol
+    MultipleUncompressedCommands,
+    CompressError(std::io::Error),
     BinCodeError(bincode::Error),
 }
 
@@ -146,19 +157,11 @@ impl std::error::Error for PacketConvertError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match &self {
             Self::PacketLengthError => None,
+            Self::HeaderSizeError => None,
             Self::BodySerializeError => None,
+            Self::MultipleUncompressedCommands => None,
+            Self::CompressError(e) => Some(e),
             Self::BinCodeError(e) => Some(e)
         }
     }
 }
-
-pub fn heartbeat_packet_binary() -> &'static [u8] {
-    &[
-        0, 0, 0, 31,    // Total length 
-        0, 16,          // Header size
-        0, 1,           // Protocol
-        0, 0, 0, 2,     // Packet type
-        0, 0, 0, 1,     // Sequence
-        91, 79, 98, 106, 101, 99, 116, 32, 111, 98, 106, 101, 99, 116, 93   // Body
-    ]
-}
\ No newline at end of file
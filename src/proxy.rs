@@ -0,0 +1,163 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use percent_encoding::percent_decode_str;
+use socks::{Socks4Stream, Socks5Stream};
+use ureq::{Proxy, ProxyProtocol};
+
+// Longest time to wait for the proxy to accept the connection and answer the tunnel request
+const PROXY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Open a TCP connection to the target host through the proxy.
+/// SOCKS4, SOCKS5 and HTTP CONNECT proxies are supported, target host name is resolved by the proxy.
+pub fn connect_through(proxy: &Proxy, host: &str, port: u16) -> io::Result<TcpStream> {
+    let proxy_addr = (proxy.host(), proxy.port());
+    let credentials = credentials(proxy);
+    let stream = match proxy.protocol() {
+        ProxyProtocol::Socks5 | ProxyProtocol::Socks5h => match &credentials {
+            Some((username, password)) => Socks5Stream::connect_with_password(proxy_addr, (host, port), username, password),
+            None => Socks5Stream::connect(proxy_addr, (host, port))
+        }?.into_inner(),
+        ProxyProtocol::Socks4 | ProxyProtocol::Socks4A => {
+            let userid = credentials.as_ref().map(|(username, _)| username.as_str()).unwrap_or("");
+            Socks4Stream::connect(proxy_addr, (host, port), userid)?.into_inner()
+        }
+        // SOCKS handshake is done by the socks crate, which has no timeout option
+        ProxyProtocol::Http => http_connect(proxy, credentials, host, port, PROXY_TIMEOUT)?,
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Proxy protocol {:?} is not supported for WebSocket connection", other)
+            ));
+        }
+    };
+    log::debug!(target: "proxy", "Tunnel to {}:{} opened through {}:{}", host, port, proxy.host(), proxy.port());
+    Ok(stream)
+}
+
+// Username and password in proxy URL are percent-encoded
+fn credentials(proxy: &Proxy) -> Option<(String, String)> {
+    let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().to_string();
+    proxy.username().map(|username| (decode(username), decode(proxy.password().unwrap_or(""))))
+}
+
+// Ask the HTTP proxy to open a tunnel with CONNECT method.
+// A silent proxy fails with a timeout error instead of blocking the connection thread forever.
+fn http_connect(
+    proxy: &Proxy,
+    credentials: Option<(String, String)>,
+    host: &str,
+    port: u16,
+    timeout: Duration
+) -> io::Result<TcpStream> {
+    let mut stream = connect_timeout(proxy.host(), proxy.port(), timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+    if let Some((username, password)) = credentials {
+        let credentials = format!("{}:{}", username, password);
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", BASE64.encode(credentials)));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    // Read the response head only, anything after it belongs to the tunnel.
+    // The proxy sends nothing else before the client starts talking, so buffering the head is safe.
+    let mut reader = BufReader::new(&stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+    let status = status_line.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("Proxy refused to open tunnel: {}", status_line.trim())
+        ));
+    }
+    // Reads of the tunnel are timed by the client
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    Ok(stream)
+}
+
+// Try every resolved address of the host, returning the last error if none accepts
+fn connect_timeout(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("Proxy host {} can not be resolved", host));
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    // Proxy answering the CONNECT request with the response, returns the proxy and the received request head
+    fn start_proxy(response: &'static str) -> (Proxy, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Proxy::new(&format!("http://user:p%40ss@{}", listener.local_addr().unwrap())).unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            // Request head ends with an empty line
+            while reader.read_line(&mut head).unwrap() > 2 {}
+            stream.write_all(response.as_bytes()).unwrap();
+            // Echo tunnel data back
+            let mut data = [0u8; 4];
+            if reader.read_exact(&mut data).is_ok() {
+                stream.write_all(&data).unwrap();
+            }
+            head
+        });
+        (proxy, handle)
+    }
+
+    #[test]
+    fn tunnel_is_opened() {
+        let (proxy, handle) = start_proxy("HTTP/1.1 200 Connection established\r\n\r\n");
+        let mut stream = http_connect(&proxy, credentials(&proxy), "example.com", 443, PROXY_TIMEOUT).unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut echo = [0u8; 4];
+        stream.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"ping");
+
+        let head = handle.join().unwrap();
+        assert!(head.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+        // Credentials are percent-decoded before encoding
+        assert!(head.contains(&format!("Proxy-Authorization: Basic {}\r\n", BASE64.encode("user:p@ss"))));
+    }
+
+    #[test]
+    fn refused_tunnel_is_error() {
+        let (proxy, _) = start_proxy("HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 0\r\n\r\n");
+        let err = http_connect(&proxy, credentials(&proxy), "example.com", 443, PROXY_TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(err.to_string().contains("407"));
+    }
+
+    #[test]
+    fn silent_proxy_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Proxy::new(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let err = http_connect(&proxy, None, "example.com", 443, Duration::from_millis(200)).unwrap_err();
+        assert!(matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+        drop(listener);
+    }
+}
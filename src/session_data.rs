@@ -1,40 +1,14 @@
 use derive_more::Display;
-use md5;
-use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
 use std::error;
-use std::fs::{self, File};
-use std::io::{self, Write};
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io;
 
-use crate::{DanmakuInfoData, HttpAPIResponse, RoomInitData, WebsocketHost};
+use crate::api::ApiClient;
+use crate::packet::http::{DanmakuInfoData, HttpAPIResponse, RoomInitData, WebsocketHost};
+use crate::wbi::{WbiKeyCache, WbiKeys, WbiSigner};
 use colored::Colorize;
 
-const WBI_CACHE_DIR: &str = ".wbi_cache";
-const WBI_CACHE_DURATION: u64 = 12 * 60 * 60; // 12 hours in seconds
-
-const MIXIN_KEY_ENC_TAB: [u8; 64] = [
-    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9, 42, 19, 29,
-    28, 14, 39, 12, 38, 41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0, 1, 60, 51, 30, 4, 22, 25,
-    54, 21, 56, 59, 6, 63, 57, 62, 11, 36, 20, 34, 44, 52,
-];
-
-fn gen_mixin_key(raw_wbi_key: impl AsRef<[u8]>) -> String {
-    let raw_wbi_key = raw_wbi_key.as_ref();
-    let mut mixin_key = {
-        let binding = MIXIN_KEY_ENC_TAB
-            .iter()
-            .map(|n| raw_wbi_key[*n as usize])
-            .collect::<Vec<u8>>();
-        // Should always success since raw_wbi_key should only consist of ASCII characters
-        String::from_utf8(binding).unwrap()
-    };
-    mixin_key.truncate(32); // Only first 32 characters
-    mixin_key
-}
-
 #[derive(Clone)]
 pub struct SessionData {
     pub room_id: u64,
@@ -47,6 +21,18 @@ pub enum InitRoomError {
     UreqError(ureq::Error),
     BadResponse(std::io::Error),
     IoError(io::Error),
+    #[display(fmt = "Live room does not exist (code 60004), check the room ID")]
+    RoomNotFound,
+    #[display(fmt = "Live room is banned until {}", _0)]
+    RoomLocked(String),
+    #[display(fmt = "Not logged in (code -101), SESSDATA may be expired, update it or remove uid to join as guest")]
+    NotLoggedIn,
+    #[display(fmt = "Request blocked by risk control (code {}), wait for a while or provide SESSDATA of a logged in account", _0)]
+    RiskControl(isize),
+    #[display(fmt = "Too many requests (code {}), wait for a while before trying again", _0)]
+    RateLimited(isize),
+    #[display(fmt = "API error (code {}): {}", code, message)]
+    Api { code: isize, message: String },
 }
 
 impl error::Error for InitRoomError {
@@ -55,6 +41,36 @@ impl error::Error for InitRoomError {
             Self::UreqError(e) => Some(e),
             Self::BadResponse(e) => Some(e),
             Self::IoError(e) => Some(e),
+            Self::RoomNotFound => None,
+            Self::RoomLocked(_) => None,
+            Self::NotLoggedIn => None,
+            Self::RiskControl(_) => None,
+            Self::RateLimited(_) => None,
+            Self::Api { .. } => None,
+        }
+    }
+}
+
+impl InitRoomError {
+    /// Map a non-zero code of API response to an error, common codes have their own variants
+    pub fn from_api_code(code: isize, message: &str) -> Self {
+        match code {
+            60004 => InitRoomError::RoomNotFound,
+            -101 => InitRoomError::NotLoggedIn,
+            -352 | -412 => InitRoomError::RiskControl(code),
+            -509 | -799 => InitRoomError::RateLimited(code),
+            _ => InitRoomError::Api { code, message: message.to_string() }
+        }
+    }
+
+    /// Permanent failures do not go away by retrying, e.g. the room does not exist or is banned.
+    /// Network errors, risk control and server errors are considered transient.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::RoomNotFound | Self::RoomLocked(_) | Self::NotLoggedIn => true,
+            // Wrong URL or rejected request, except rate limiting
+            Self::UreqError(ureq::Error::StatusCode(code)) => (400..500).contains(code) && *code != 429,
+            _ => false
         }
     }
 }
@@ -67,8 +83,22 @@ impl From<std::io::Error> for InitRoomError {
 
 impl From<ureq::Error> for InitRoomError {
     fn from(err: ureq::Error) -> Self {
-        InitRoomError::UreqError(err)
+        match err {
+            // Blocked requests are sometimes answered with HTTP status instead of response code
+            ureq::Error::StatusCode(412) => InitRoomError::RiskControl(-412),
+            err => InitRoomError::UreqError(err)
+        }
+    }
+}
+
+// Check the response code before deserializing data, data of error responses is usually null or empty
+fn read_api_data<T: DeserializeOwned>(response: HttpAPIResponse<serde_json::Value>) -> Result<T, InitRoomError> {
+    if !response.ok() {
+        log::debug!(target: "main", "API responded with code {}: {}", response.code(), response.message());
+        return Err(InitRoomError::from_api_code(response.code(), response.message()));
     }
+    serde_json::from_value(response.response_data())
+        .map_err(|e| InitRoomError::BadResponse(io::Error::new(io::ErrorKind::InvalidData, e)))
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -77,19 +107,16 @@ struct BuvidResponse {
     b_4: String,
 }
 
-fn get_buvid_cookies() -> Result<(String, String), InitRoomError> {
+fn get_buvid_cookies(api: &ApiClient) -> Result<(String, String), InitRoomError> {
     log::debug!(
         target: "main",
         "Requesting buvid3 and buvid4 from finger API..."
     );
 
-    let buvid_data: BuvidResponse = ureq::get("https://api.bilibili.com/x/frontend/finger/spi")
-        .header("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/58.0.3029.110 Safari/537.3")
-        .header("Referer", "https://www.bilibili.com/")
-        .call()?
-        .body_mut()
-        .read_json::<HttpAPIResponse<BuvidResponse>>()?
-        .response_data();
+    let buvid_data: BuvidResponse = read_api_data(api.get_json(
+        &api.url("/x/frontend/finger/spi"),
+        &[("Referer", "https://www.bilibili.com/")]
+    )?)?;
 
     log::debug!(
         target: "main",
@@ -101,51 +128,22 @@ fn get_buvid_cookies() -> Result<(String, String), InitRoomError> {
     Ok((buvid_data.b_3, buvid_data.b_4))
 }
 
-fn get_wbi_keys() -> Result<(String, String), InitRoomError> {
-    // Create cache directory if it doesn't exist
-    fs::create_dir_all(WBI_CACHE_DIR)?;
-
-    let img_key_path = Path::new(WBI_CACHE_DIR).join("img_key");
-    let sub_key_path = Path::new(WBI_CACHE_DIR).join("sub_key");
-    let timestamp_path = Path::new(WBI_CACHE_DIR).join("timestamp");
-
-    // Check if we have cached keys and if they're still valid
-    if img_key_path.exists() && sub_key_path.exists() && timestamp_path.exists() {
-        let timestamp = fs::read_to_string(&timestamp_path)?
-            .parse::<u64>()
-            .unwrap_or(0);
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        if current_time - timestamp < WBI_CACHE_DURATION {
-            // Cache is still valid, read the keys
-            let img_key = fs::read_to_string(&img_key_path)?;
-            let sub_key = fs::read_to_string(&sub_key_path)?;
-            log::debug!(
-                target: "main",
-                "Using cached WBI keys - img_key: {}, sub_key: {}",
-                img_key.bright_green(),
-                sub_key.bright_green()
-            );
-            return Ok((img_key, sub_key));
-        }
-    }
-
-    // Cache is invalid or doesn't exist, get new keys
+// Request WBI keys from nav API and save them to the cache
+fn fetch_wbi_keys(api: &ApiClient, cache: &WbiKeyCache) -> Result<WbiKeys, InitRoomError> {
     log::debug!(
         target: "main",
         "Requesting WBI keys from nav API..."
     );
 
-    let nav_data: serde_json::Value = ureq::get("https://api.bilibili.com/x/web-interface/nav")
-        .header("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/58.0.3029.110 Safari/537.3")
-        .header("Referer", "https://www.bilibili.com/")
-        .call()?
-        .body_mut()
-        .read_json::<HttpAPIResponse<serde_json::Value>>()?
-        .response_data();
+    let nav_response = api.get_json(
+        &api.url("/x/web-interface/nav"),
+        &[("Referer", "https://www.bilibili.com/")]
+    )?;
+    // Nav API answers guests with -101 but still returns WBI keys
+    if !nav_response.ok() && nav_response.code() != -101 {
+        return Err(InitRoomError::from_api_code(nav_response.code(), nav_response.message()));
+    }
+    let nav_data = nav_response.response_data();
 
     let wbi_img = nav_data.get("wbi_img").ok_or_else(|| {
         log::error!(
@@ -204,71 +202,69 @@ fn get_wbi_keys() -> Result<(String, String), InitRoomError> {
         .next()
         .unwrap_or("");
 
-    // Save the new keys and timestamp
-    let mut img_key_file = File::create(&img_key_path)?;
-    let mut sub_key_file = File::create(&sub_key_path)?;
-    let mut timestamp_file = File::create(&timestamp_path)?;
-
-    img_key_file.write_all(img_key.as_bytes())?;
-    sub_key_file.write_all(sub_key.as_bytes())?;
-    timestamp_file.write_all(
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            .to_string()
-            .as_bytes(),
-    )?;
-
-    log::debug!(
-        target: "main",
-        "Cached new WBI keys - img_key: {}, sub_key: {}",
-        img_key.bright_green(),
-        sub_key.bright_green()
-    );
+    let keys = WbiKeys {
+        img_key: img_key.to_string(),
+        sub_key: sub_key.to_string(),
+    };
+    if !keys.is_valid() {
+        return Err(InitRoomError::BadResponse(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid WBI keys in nav response: {}, {}", img_key, sub_key),
+        )));
+    }
 
-    Ok((img_key.to_string(), sub_key.to_string()))
-}
+    // Failing to cache the keys only costs a request next time
+    match cache.store(&keys) {
+        Ok(()) => log::debug!(
+            target: "main",
+            "Cached new WBI keys to {} - img_key: {}, sub_key: {}",
+            cache.path().display(),
+            img_key.bright_green(),
+            sub_key.bright_green()
+        ),
+        Err(e) => log::warn!(target: "main", "Failed to write WBI key cache {}: {}", cache.path().display(), e)
+    }
 
-fn url_encode(s: &str) -> String {
-    utf8_percent_encode(s, NON_ALPHANUMERIC)
-        .to_string()
-        .replace('+', "%20")
+    Ok(keys)
 }
 
-fn calculate_w_rid(params: &BTreeMap<&str, String>, mixin_key: &str) -> String {
-    // Sort parameters by key and encode values
-    let encoded_params: Vec<String> = params
-        .iter()
-        .map(|(k, v)| format!("{}={}", k, url_encode(v)))
-        .collect();
-
-    // Join parameters with &
-    let param_string = encoded_params.join("&");
-
-    // Append mixin_key
-    let string_to_hash = format!("{}{}", param_string, mixin_key);
-
-    // Calculate MD5
-    let result = md5::compute(string_to_hash.as_bytes());
-
-    // Convert to hex string
-    format!("{:x}", result)
+// Request danmaku server list and token with WBI signed query
+fn get_danmaku_info(
+    api: &ApiClient,
+    room_id: u64,
+    keys: &WbiKeys,
+    cookie_string: &str
+) -> Result<DanmakuInfoData, InitRoomError> {
+    let signer = WbiSigner::new(keys).ok_or_else(|| InitRoomError::BadResponse(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Invalid WBI keys",
+    )))?;
+    let query_string = signer.sign([("id", room_id.to_string())]);
+
+    read_api_data(api.get_json(
+        &api.live_url(&format!("/xlive/web-room/v1/index/getDanmuInfo?{query_string}")),
+        &[("Cookie", cookie_string)]
+    )?)
 }
 
 pub fn init_room_data(
     room_id: u64,
     uid: Option<u64>,
     sessdata: &Option<String>,
+    api: &ApiClient,
 ) -> Result<(SessionData, Vec<WebsocketHost>), InitRoomError> {
     // Get room data for the real room id
-    let room_data: RoomInitData = ureq::get(&format!(
-            "https://api.live.bilibili.com/room/v1/Room/room_init?id={room_id}",
-        ))
-        .call()?
-        .body_mut()
-        .read_json::<HttpAPIResponse<RoomInitData>>()?
-        .response_data();
+    let room_data: RoomInitData = read_api_data(api.get_json(
+        &api.live_url(&format!("/room/v1/Room/room_init?id={room_id}")),
+        &[]
+    )?)?;
+
+    if room_data.is_locked {
+        let lock_till = chrono::DateTime::from_timestamp(room_data.lock_till, 0)
+            .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| room_data.lock_till.to_string());
+        return Err(InitRoomError::RoomLocked(lock_till));
+    }
 
     let room_id = room_data.room_id;
     log::debug!(
@@ -277,34 +273,24 @@ pub fn init_room_data(
     );
 
     // Get WBI keys (from cache or API)
-    let (img_key, sub_key) = get_wbi_keys()?;
+    let cache = WbiKeyCache::new(&api.config().wbi_cache_dir);
+    let cached_keys = cache.load();
+    let keys_from_cache = cached_keys.is_some();
+    let keys = match cached_keys {
+        Some(keys) => {
+            log::debug!(
+                target: "main",
+                "Using cached WBI keys - img_key: {}, sub_key: {}",
+                keys.img_key.bright_green(),
+                keys.sub_key.bright_green()
+            );
+            keys
+        }
+        None => fetch_wbi_keys(api, &cache)?
+    };
 
     // Get buvid3 and buvid4 cookies
-    let (buvid3, buvid4) = get_buvid_cookies()?;
-
-    // Get raw_wbi_key
-    let raw_wbi_key = format!("{}{}", img_key, sub_key);
-
-    // Get mixin_key
-    let mixin_key = gen_mixin_key(raw_wbi_key.as_bytes());
-
-    // Get wts
-    let wts = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        .to_string();
-
-    // Create sorted parameters map
-    let mut params = BTreeMap::new();
-    params.insert("id", room_id.to_string());
-    params.insert("wts", wts);
-
-    // Calculate w_rid
-    let w_rid = calculate_w_rid(&params, &mixin_key);
-
-    // Build final query string
-    let query_string = format!("id={}&wts={}&w_rid={}", room_id, params["wts"], w_rid);
+    let (buvid3, buvid4) = get_buvid_cookies(api)?;
 
     // Build cookie string with SESSDATA, buvid3, and buvid4
     let mut cookie_parts = Vec::new();
@@ -317,15 +303,20 @@ pub fn init_room_data(
     cookie_parts.push(format!("buvid4={}", buvid4));
     let cookie_string = cookie_parts.join("; ");
 
-    // Get danmaku info data
-    let danmaku_info_data: DanmakuInfoData = ureq::get(&format!(
-            "https://api.live.bilibili.com/xlive/web-room/v1/index/getDanmuInfo?{query_string}",
-        ))
-        .header("Cookie", &cookie_string)
-        .call()?
-        .body_mut()
-        .read_json::<HttpAPIResponse<DanmakuInfoData>>()?
-        .response_data();
+    // Get danmaku info data, rejected signature usually means the keys have been rotated
+    let danmaku_info_data = match get_danmaku_info(api, room_id, &keys, &cookie_string) {
+        Err(e @ (InitRoomError::RiskControl(_) | InitRoomError::Api { .. } | InitRoomError::BadResponse(_))) => {
+            log::warn!(target: "main", "WBI signed request failed ({}), invalidating cached WBI keys", e);
+            cache.invalidate();
+            // Fresh keys are not going to work better
+            if !keys_from_cache {
+                return Err(e);
+            }
+            let keys = fetch_wbi_keys(api, &cache)?;
+            get_danmaku_info(api, room_id, &keys, &cookie_string)?
+        }
+        result => result?
+    };
 
     let token = danmaku_info_data.token;
     return Ok((
@@ -0,0 +1,397 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+
+// Keys are rotated daily, refresh them a bit earlier
+const WBI_CACHE_DURATION_SEC: u64 = 12 * 60 * 60;
+
+const WBI_CACHE_FILE: &str = "wbi_keys.json";
+
+// Numbers temporary cache files, rooms may store keys from several threads at once
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+const MIXIN_KEY_ENC_TAB: [u8; 64] = [
+    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9, 42, 19, 29,
+    28, 14, 39, 12, 38, 41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0, 1, 60, 51, 30, 4, 22, 25,
+    54, 21, 56, 59, 6, 63, 57, 62, 11, 36, 20, 34, 44, 52,
+];
+
+// Characters stripped from parameter values before signing
+const FILTERED_CHARS: [char; 5] = ['!', '\'', '(', ')', '*'];
+
+// Same as encodeURIComponent once the filtered characters are gone
+const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Key pair used for signing WBI requests, taken from file names of `wbi_img` in nav API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WbiKeys {
+    pub img_key: String,
+    pub sub_key: String,
+}
+
+impl WbiKeys {
+    /// Both keys are 32 alphanumeric characters, anything else can not be used for signing
+    pub fn is_valid(&self) -> bool {
+        let valid = |key: &str| key.len() == 32 && key.chars().all(|c| c.is_ascii_alphanumeric());
+        valid(&self.img_key) && valid(&self.sub_key)
+    }
+}
+
+/// Signs query parameters of WBI APIs with the mixin key derived from `WbiKeys`.
+///
+/// Signing adds `wts` to the parameters, strips `!'()*` from values, sorts parameters by key
+/// and appends `w_rid`, the MD5 of the encoded query followed by the mixin key.
+///
+/// ```
+/// use bilibili_live_danmaku_cli::wbi::{WbiKeys, WbiSigner};
+///
+/// // Published example of WBI signing
+/// let keys = WbiKeys {
+///     img_key: "7cd084941338484aae1ad9425b84077c".to_string(),
+///     sub_key: "4932caff0ff746eab6f01bf08b70ac45".to_string(),
+/// };
+/// let signer = WbiSigner::new(&keys).unwrap();
+/// assert_eq!(signer.mixin_key(), "ea1db124af3c7062474693fa704f4ff8");
+/// assert_eq!(
+///     signer.sign_with_timestamp([("foo", "114"), ("bar", "514"), ("zab", "1919810")], 1702204169),
+///     "bar=514&foo=114&wts=1702204169&zab=1919810&w_rid=8f6f2b5b3d485fe1886cec6a0be8c5d4"
+/// );
+///
+/// // Filtered characters do not take part in the signature
+/// assert_eq!(
+///     signer.sign_with_timestamp([("keyword", "it's (a) test!*")], 1702204169),
+///     signer.sign_with_timestamp([("keyword", "its a test")], 1702204169)
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct WbiSigner {
+    mixin_key: String,
+}
+
+impl WbiSigner {
+    /// Derive the mixin key, returns None if the keys are not valid
+    pub fn new(keys: &WbiKeys) -> Option<Self> {
+        if !keys.is_valid() {
+            return None;
+        }
+        let raw_wbi_key = format!("{}{}", keys.img_key, keys.sub_key);
+        let raw_wbi_key = raw_wbi_key.as_bytes();
+        let mixin_key: String = MIXIN_KEY_ENC_TAB
+            .iter()
+            .take(32)
+            .map(|n| raw_wbi_key[*n as usize] as char)
+            .collect();
+        Some(WbiSigner { mixin_key })
+    }
+
+    pub fn mixin_key(&self) -> &str {
+        &self.mixin_key
+    }
+
+    /// Sign parameters with current time and return the query string including `wts` and `w_rid`
+    pub fn sign<K, V>(&self, params: impl IntoIterator<Item = (K, V)>) -> String
+    where
+        K: Into<String>,
+        V: ToString,
+    {
+        self.sign_with_timestamp(params, now_sec())
+    }
+
+    /// Sign parameters with the given Unix time in seconds as `wts`
+    pub fn sign_with_timestamp<K, V>(&self, params: impl IntoIterator<Item = (K, V)>, wts: u64) -> String
+    where
+        K: Into<String>,
+        V: ToString,
+    {
+        let mut params: BTreeMap<String, String> = params.into_iter()
+            .map(|(key, value)| {
+                let value: String = value.to_string().chars().filter(|c| !FILTERED_CHARS.contains(c)).collect();
+                (key.into(), value)
+            })
+            .collect();
+        params.insert("wts".to_string(), wts.to_string());
+
+        // Keys are sorted by the map
+        let query = params.iter()
+            .map(|(key, value)| format!("{}={}", url_encode(key), url_encode(value)))
+            .collect::<Vec<String>>()
+            .join("&");
+        let w_rid = md5::compute(format!("{}{}", query, self.mixin_key).as_bytes());
+        format!("{}&w_rid={:x}", query, w_rid)
+    }
+}
+
+fn url_encode(s: &str) -> String {
+    utf8_percent_encode(s, QUERY_ENCODE_SET).to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    #[serde(flatten)]
+    keys: WbiKeys,
+    // Unix time in seconds
+    fetched_at: u64,
+}
+
+/// Per-user cache directory of this tool, falls back to current directory if the platform has none
+pub fn default_cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .map(|dir| dir.join("bilibili-live-danmaku-cli"))
+        .unwrap_or_else(|| PathBuf::from(".wbi_cache"))
+}
+
+/// WBI keys cached on disk, so they are not requested on every start
+#[derive(Debug, Clone)]
+pub struct WbiKeyCache {
+    path: PathBuf,
+}
+
+impl WbiKeyCache {
+    pub fn new(dir: &Path) -> Self {
+        WbiKeyCache {
+            path: dir.join(WBI_CACHE_FILE),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read cached keys if they are fresh. Corrupt or stale cache is removed so it is rewritten next time.
+    pub fn load(&self) -> Option<WbiKeys> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    log::warn!(target: "wbi", "Failed to read WBI key cache {}: {}", self.path.display(), e);
+                }
+                return None;
+            }
+        };
+        let entry = match serde_json::from_str::<CacheEntry>(&content) {
+            Ok(entry) if entry.keys.is_valid() => entry,
+            _ => {
+                log::warn!(target: "wbi", "WBI key cache {} is corrupt, removing it", self.path.display());
+                self.invalidate();
+                return None;
+            }
+        };
+        // Time going backwards also makes the cache stale
+        let age = now_sec().checked_sub(entry.fetched_at).unwrap_or(u64::MAX);
+        if age >= WBI_CACHE_DURATION_SEC {
+            log::debug!(target: "wbi", "WBI key cache is stale, fetched {} seconds ago", age);
+            self.invalidate();
+            return None;
+        }
+        Some(entry.keys)
+    }
+
+    /// Write keys to a temporary file and rename it over the cache, readers never see a partial file
+    pub fn store(&self, keys: &WbiKeys) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let entry = CacheEntry {
+            keys: keys.clone(),
+            fetched_at: now_sec(),
+        };
+        let content = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+        let temp_path = self.path.with_extension(format!(
+            "json.{}.{}.tmp",
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp_path, content)?;
+        fs::rename(&temp_path, &self.path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp_path);
+        })
+    }
+
+    /// Remove cached keys, called when a signed request is rejected
+    pub fn invalidate(&self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => log::debug!(target: "wbi", "Removed WBI key cache {}", self.path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!(target: "wbi", "Failed to remove WBI key cache {}: {}", self.path.display(), e)
+        }
+    }
+}
+
+fn now_sec() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> WbiKeys {
+        WbiKeys {
+            img_key: "7cd084941338484aae1ad9425b84077c".to_string(),
+            sub_key: "4932caff0ff746eab6f01bf08b70ac45".to_string(),
+        }
+    }
+
+    fn signer() -> WbiSigner {
+        WbiSigner::new(&keys()).unwrap()
+    }
+
+    // Query without w_rid, checking w_rid matches it
+    fn signed_query(signed: &str) -> &str {
+        let (query, w_rid) = signed.rsplit_once("&w_rid=").unwrap();
+        let expected = md5::compute(format!("{}{}", query, signer().mixin_key()).as_bytes());
+        assert_eq!(w_rid, format!("{:x}", expected));
+        query
+    }
+
+    #[test]
+    fn published_vector() {
+        let signer = signer();
+        assert_eq!(signer.mixin_key(), "ea1db124af3c7062474693fa704f4ff8");
+        assert_eq!(
+            signer.sign_with_timestamp([("foo", "114"), ("bar", "514"), ("zab", "1919810")], 1702204169),
+            "bar=514&foo=114&wts=1702204169&zab=1919810&w_rid=8f6f2b5b3d485fe1886cec6a0be8c5d4"
+        );
+    }
+
+    #[test]
+    fn filtered_chars_are_stripped() {
+        let signed = signer().sign_with_timestamp([("keyword", "it's (a) test!*")], 1702204169);
+        assert_eq!(signed_query(&signed), "keyword=its%20a%20test&wts=1702204169");
+    }
+
+    #[test]
+    fn values_are_percent_encoded() {
+        let signed = signer().sign_with_timestamp([("keyword", "弹幕 a&b=c/d+e~f.g-h_i")], 1702204169);
+        assert_eq!(
+            signed_query(&signed),
+            "keyword=%E5%BC%B9%E5%B9%95%20a%26b%3Dc%2Fd%2Be~f.g-h_i&wts=1702204169"
+        );
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        let short = WbiKeys { img_key: "7cd08494".to_string(), ..keys() };
+        assert!(WbiSigner::new(&short).is_none());
+        let non_alphanumeric = WbiKeys { sub_key: "4932caff0ff746eab6f01bf08b70ac4/".to_string(), ..keys() };
+        assert!(WbiSigner::new(&non_alphanumeric).is_none());
+        let non_ascii = WbiKeys { sub_key: "4932caff0ff746eab6f01bf08b70a弹".to_string(), ..keys() };
+        assert!(WbiSigner::new(&non_ascii).is_none());
+    }
+
+    #[test]
+    fn parameters_are_sorted_and_wts_is_replaced() {
+        let signed = signer().sign_with_timestamp(
+            [("zab", "1919810"), ("wts", "1"), ("bar", "514"), ("foo", "114")],
+            1702204169
+        );
+        assert_eq!(signed, "bar=514&foo=114&wts=1702204169&zab=1919810&w_rid=8f6f2b5b3d485fe1886cec6a0be8c5d4");
+    }
+
+    // Every test has its own cache directory
+    fn cache(name: &str) -> WbiKeyCache {
+        let dir = std::env::temp_dir().join(format!("bilibili-live-danmaku-cli-wbi-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        WbiKeyCache::new(&dir)
+    }
+
+    fn write_entry(cache: &WbiKeyCache, fetched_at: u64) {
+        fs::create_dir_all(cache.path().parent().unwrap()).unwrap();
+        let entry = CacheEntry { keys: keys(), fetched_at };
+        fs::write(cache.path(), serde_json::to_vec(&entry).unwrap()).unwrap();
+    }
+
+    fn remove(cache: WbiKeyCache) {
+        let _ = fs::remove_dir_all(cache.path().parent().unwrap());
+    }
+
+    #[test]
+    fn stored_keys_are_loaded() {
+        let cache = cache("round-trip");
+        assert!(cache.load().is_none());
+        cache.store(&keys()).unwrap();
+
+        let loaded = cache.load().unwrap();
+        assert_eq!(loaded.img_key, keys().img_key);
+        assert_eq!(loaded.sub_key, keys().sub_key);
+        // Temporary file is renamed over the cache
+        let files: Vec<_> = fs::read_dir(cache.path().parent().unwrap()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        remove(cache);
+    }
+
+    #[test]
+    fn concurrent_stores_do_not_conflict() {
+        let cache = cache("concurrent");
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        cache.store(&keys()).unwrap();
+                    }
+                });
+            }
+        });
+        assert!(cache.load().is_some());
+        let files: Vec<_> = fs::read_dir(cache.path().parent().unwrap()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        remove(cache);
+    }
+
+    #[test]
+    fn corrupt_cache_is_removed() {
+        let cache = cache("corrupt");
+        fs::create_dir_all(cache.path().parent().unwrap()).unwrap();
+        fs::write(cache.path(), b"\x00garbage{").unwrap();
+        assert!(cache.load().is_none());
+        assert!(!cache.path().exists());
+
+        // Well formed entry with unusable keys is corrupt too
+        fs::write(cache.path(), br#"{"img_key":"short","sub_key":"short","fetched_at":0}"#).unwrap();
+        assert!(cache.load().is_none());
+        assert!(!cache.path().exists());
+        remove(cache);
+    }
+
+    #[test]
+    fn cache_expires_after_duration() {
+        let cache = cache("expiry");
+        write_entry(&cache, now_sec() - WBI_CACHE_DURATION_SEC + 60);
+        assert!(cache.load().is_some());
+
+        write_entry(&cache, now_sec() - WBI_CACHE_DURATION_SEC);
+        assert!(cache.load().is_none());
+        assert!(!cache.path().exists());
+
+        // Fetched in the future, the clock has gone backwards
+        write_entry(&cache, now_sec() + 60);
+        assert!(cache.load().is_none());
+        assert!(!cache.path().exists());
+        remove(cache);
+    }
+
+    #[test]
+    fn invalidated_cache_is_not_loaded() {
+        let cache = cache("invalidate");
+        cache.store(&keys()).unwrap();
+        cache.invalidate();
+        assert!(!cache.path().exists());
+        assert!(cache.load().is_none());
+        // Invalidating a missing cache is fine
+        cache.invalidate();
+        remove(cache);
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::TimeDelta;
+use serde_json::{json, Value};
+
+use bilibili_live_danmaku_cli::dedup::MessageDeduplicator;
+use bilibili_live_danmaku_cli::history::{fetch_danmaku_history, HistoryError};
+use bilibili_live_danmaku_cli::message::RawLiveMessage;
+use bilibili_live_danmaku_cli::{init_room_data, ApiClient, ApiConfig, InitRoomError};
+
+// Answers a request path with status and JSON body, given how many times the path was requested before
+type Responder = fn(&str, usize) -> (u16, Value);
+
+// Minimal HTTP server answering every request with the responder, returns base URL and requested paths
+fn start_stub(responder: Responder) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(vec![]));
+    let recorded = requests.clone();
+    thread::spawn(move || {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            // Skip headers, requests have no body
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let path = request_line.split_whitespace().nth(1).unwrap_or("").to_string();
+            let endpoint = path.split('?').next().unwrap_or("").to_string();
+            let count = counts.entry(endpoint.clone()).or_insert(0);
+            let (status, body) = responder(&endpoint, *count);
+            *count += 1;
+            recorded.lock().unwrap().push(path);
+
+            let body = body.to_string();
+            let response = format!(
+                "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (base_url, requests)
+}
+
+// Responses of a healthy API, room 1 is the short ID of room 1001
+fn api_response(endpoint: &str) -> (u16, Value) {
+    let body = match endpoint {
+        "/room/v1/Room/room_init" => json!({
+            "code": 0, "message": "0",
+            "data": { "room_id": 1001, "short_id": 1, "uid": 9, "live_status": 1 }
+        }),
+        // Guests get -101 with WBI keys
+        "/x/web-interface/nav" => json!({
+            "code": -101, "message": "账号未登录",
+            "data": { "wbi_img": {
+                "img_url": "https://i0.hdslb.com/bfs/wbi/7cd084941338484aae1ad9425b84077c.png",
+                "sub_url": "https://i0.hdslb.com/bfs/wbi/4932caff0ff746eab6f01bf08b70ac45.png"
+            } }
+        }),
+        "/x/frontend/finger/spi" => json!({ "code": 0, "message": "ok", "data": { "b_3": "B3", "b_4": "B4" } }),
+        "/xlive/web-room/v1/index/getDanmuInfo" => json!({
+            "code": 0, "message": "0",
+            "data": { "token": "TOKEN", "host_list": [{ "host": "127.0.0.1", "port": 2243, "wss_port": 443, "ws_port": 2244 }] }
+        }),
+        _ => return (404, json!({ "code": -404, "message": "啥都木有" }))
+    };
+    (200, body)
+}
+
+fn api_client(base_url: &str, retries: u32, name: &str) -> ApiClient {
+    ApiClient::new(ApiConfig {
+        live_base_url: base_url.to_string(),
+        base_url: base_url.to_string(),
+        retries,
+        wbi_cache_dir: cache_dir(name),
+        ..ApiConfig::default()
+    })
+}
+
+// Every test has its own WBI key cache
+fn cache_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bilibili-live-danmaku-cli-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn count(requests: &Mutex<Vec<String>>, endpoint: &str) -> usize {
+    requests.lock().unwrap().iter().filter(|path| path.starts_with(endpoint)).count()
+}
+
+#[test]
+fn init_room_data_against_stub() {
+    let (base_url, requests) = start_stub(|endpoint, _| api_response(endpoint));
+    let api = api_client(&base_url, 0, "init");
+
+    let (session, hosts) = init_room_data(1, None, &None, &api).ok().unwrap();
+    assert_eq!(session.room_id, 1001);
+    assert_eq!(session.token, "TOKEN");
+    assert_eq!(hosts.len(), 1);
+    assert_eq!(hosts[0].wss_port, 443);
+
+    let requests = requests.lock().unwrap();
+    let danmaku_info = requests.iter().find(|path| path.starts_with("/xlive/web-room/v1/index/getDanmuInfo")).unwrap();
+    assert!(danmaku_info.contains("id=1001") && danmaku_info.contains("wts=") && danmaku_info.contains("w_rid="));
+    let _ = std::fs::remove_dir_all(api.config().wbi_cache_dir.clone());
+}
+
+#[test]
+fn server_errors_are_retried() {
+    // Room data fails once with 503 before succeeding
+    let (base_url, requests) = start_stub(|endpoint, count| match (endpoint, count) {
+        ("/room/v1/Room/room_init", 0) => (503, json!({})),
+        _ => api_response(endpoint)
+    });
+    let api = api_client(&base_url, 2, "retry");
+    assert!(init_room_data(1, None, &None, &api).is_ok());
+    assert_eq!(count(&requests, "/room/v1/Room/room_init"), 2);
+    let _ = std::fs::remove_dir_all(api.config().wbi_cache_dir.clone());
+
+    // Out of retries, the status is reported as a transient error
+    let (base_url, requests) = start_stub(|_, _| (503, json!({})));
+    let api = api_client(&base_url, 1, "retry-exhausted");
+    let err = init_room_data(1, None, &None, &api).err().unwrap();
+    assert!(matches!(err, InitRoomError::UreqError(ureq::Error::StatusCode(503))));
+    assert!(!err.is_permanent());
+    assert_eq!(count(&requests, "/room/v1/Room/room_init"), 2);
+}
+
+#[test]
+fn api_codes_are_mapped_to_errors() {
+    let (base_url, _) = start_stub(|_, _| (200, json!({ "code": 60004, "message": "直播间不存在", "data": null })));
+    let err = init_room_data(1, None, &None, &api_client(&base_url, 0, "not-found")).err().unwrap();
+    assert!(matches!(err, InitRoomError::RoomNotFound));
+    assert!(err.is_permanent());
+
+    let (base_url, requests) = start_stub(|_, _| (200, json!({ "code": -352, "message": "-352", "data": { "v_voucher": "x" } })));
+    let err = init_room_data(1, None, &None, &api_client(&base_url, 2, "risk-control")).err().unwrap();
+    assert!(matches!(err, InitRoomError::RiskControl(-352)));
+    assert!(!err.is_permanent());
+    // Error codes are not retried by the client, the caller decides
+    assert_eq!(count(&requests, "/room/v1/Room/room_init"), 1);
+
+    let (base_url, _) = start_stub(|_, _| (200, json!({ "code": 19002003, "message": "房间信息不存在" })));
+    let err = init_room_data(1, None, &None, &api_client(&base_url, 0, "other")).err().unwrap();
+    assert!(matches!(err, InitRoomError::Api { code: 19002003, .. }));
+}
+
+// Recent danmaku of room 1001: full user info, nickname only, and an entry without text
+fn history_response() -> (u16, Value) {
+    (200, json!({
+        "code": 0, "message": "0",
+        "data": { "admin": [], "room": [
+            {
+                "text": "hello", "uid": 5, "nickname": "alice", "isadmin": 0, "vip": 0, "guard_level": 3,
+                "id_str": "id-hello", "check_info": { "ts": 1700000000, "ct": "CT-HELLO" },
+                "user": { "uid": 5, "base": { "name": "alice" }, "guard": null, "medal": null }
+            },
+            {
+                "text": "world", "uid": 6, "nickname": "bob", "isadmin": 1, "vip": 0, "guard_level": 0,
+                "check_info": { "ts": 1700000001, "ct": "CT-WORLD" }
+            },
+            { "uid": 7, "nickname": "carol", "check_info": { "ts": 1700000002, "ct": "CT-BROKEN" } }
+        ] }
+    }))
+}
+
+#[test]
+fn danmaku_history_against_stub() {
+    let (base_url, requests) = start_stub(|endpoint, _| match endpoint {
+        "/xlive/web-room/v1/dM/gethistory" => history_response(),
+        _ => api_response(endpoint)
+    });
+    let history = fetch_danmaku_history(&api_client(&base_url, 0, "history"), 1001).unwrap();
+    assert!(requests.lock().unwrap()[0].contains("roomid=1001"));
+
+    // Entry without text is skipped
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].info.text, "hello");
+    assert_eq!(history[0].info.user.username, "alice");
+    assert!(history[0].info.user.guard_level.is_some());
+    assert!(history[0].info.backfilled);
+    assert_eq!(history[0].send_time.timestamp(), 1700000000);
+    assert_eq!(history[0].identities, ["DANMU_MSG:id:id-hello", "DANMU_MSG:ct:CT-HELLO"]);
+    // Older entries without user fall back to nickname
+    assert_eq!((history[1].info.user.uid, history[1].info.user.username.as_str()), (6, "bob"));
+    assert!(history[1].info.is_admin);
+    assert_eq!(history[1].identities, ["DANMU_MSG:ct:CT-WORLD"]);
+
+    let (base_url, _) = start_stub(|_, _| (200, json!({ "code": -352, "message": "-352", "data": null })));
+    let err = fetch_danmaku_history(&api_client(&base_url, 0, "history-error"), 1001).unwrap_err();
+    assert!(matches!(err, HistoryError::Api { code: -352, .. }));
+}
+
+#[test]
+fn history_danmaku_matches_live_danmaku() {
+    let (base_url, _) = start_stub(|_, _| history_response());
+    let history = fetch_danmaku_history(&api_client(&base_url, 0, "history-dedup"), 1001).unwrap();
+
+    // The same danmaku as pushed by the live connection
+    let live: RawLiveMessage = serde_json::from_value(json!({
+        "cmd": "DANMU_MSG",
+        "info": [
+            [0, 1, 25, 16777215, 1700000000000u64, 0, 0, "", 0, 0, 0, "", 0, "{}", "{}",
+                { "extra": "{\"id_str\":\"id-hello\"}" }],
+            "hello",
+            [5, "alice", 0, 0],
+            [], [], [], 0, 0, {},
+            { "ts": 1700000000, "ct": "CT-HELLO" }
+        ]
+    })).unwrap();
+    let mut deduplicator = MessageDeduplicator::danmaku_only(TimeDelta::seconds(300));
+    assert!(!deduplicator.is_duplicate(0, &live));
+    assert!(deduplicator.is_duplicate_identities(&history[0].identities));
+    assert!(!deduplicator.is_duplicate_identities(&history[1].identities));
+}
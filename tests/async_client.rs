@@ -0,0 +1,52 @@
+#![cfg(feature = "async")]
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use serde_json::json;
+use tokio::time::timeout;
+
+use bilibili_live_danmaku_cli::mock_server::MockServer;
+use bilibili_live_danmaku_cli::{AsyncLiveClient, ClientError, LiveMessage, Protover};
+
+mod common;
+
+use common::{session, start_server, TEST_TIMEOUT};
+
+#[tokio::test]
+async fn stream_yields_messages_until_server_disconnects() {
+    let fixtures = vec![
+        json!({ "cmd": "LIVE", "roomid": 4793604 }),
+        json!({ "cmd": "WARNING", "msg": "mock warning" }),
+    ];
+    let url = start_server(MockServer::bind("127.0.0.1:0", fixtures).unwrap().disconnect_after_fixtures());
+    let client = AsyncLiveClient::connect(&url, session(), Protover::Brotli).await.unwrap();
+
+    // Mock server closes the connection if the first packet is not a certificate, so fixtures prove it was sent
+    let stream = client.into_stream(Duration::from_secs(20), Duration::from_secs(60));
+    let results: Vec<_> = timeout(TEST_TIMEOUT, stream.collect()).await.expect("stream did not end in time");
+    let messages: Vec<LiveMessage> = results.into_iter().filter_map(Result::ok).collect();
+    assert_eq!(messages.len(), 2);
+    assert!(matches!(&messages[0], LiveMessage::LiveStart(info) if info.room_id == 4793604));
+    assert!(matches!(&messages[1], LiveMessage::Warning(info) if info.message == "mock warning"));
+}
+
+#[tokio::test]
+async fn silent_connection_times_out() {
+    // Accepts the WebSocket handshake and then never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/sub", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let _socket = tungstenite::accept(stream).unwrap();
+        thread::sleep(TEST_TIMEOUT);
+    });
+    let client = AsyncLiveClient::connect(&url, session(), Protover::Brotli).await.unwrap();
+
+    let mut stream = Box::pin(client.into_stream(Duration::from_millis(100), Duration::from_millis(500)));
+    let result = timeout(TEST_TIMEOUT, stream.next()).await.expect("watchdog did not fire in time");
+    assert!(matches!(result, Some(Err(ClientError::HeartbeatTimeout))));
+    assert!(stream.next().await.is_none());
+}
@@ -0,0 +1,19 @@
+use std::thread;
+use std::time::Duration;
+
+use bilibili_live_danmaku_cli::mock_server::MockServer;
+use bilibili_live_danmaku_cli::SessionData;
+
+// Longest time a test waits for the client before failing instead of hanging
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Serve on a random local port, returns the WebSocket URL
+pub fn start_server(server: MockServer) -> String {
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.serve());
+    format!("ws://{}/sub", addr)
+}
+
+pub fn session() -> SessionData {
+    SessionData { room_id: 4793604, uid: 0, token: String::new() }
+}
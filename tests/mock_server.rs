@@ -0,0 +1,169 @@
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::TimeDelta;
+use serde_json::{json, Value};
+
+use bilibili_live_danmaku_cli::mock_server::MockServer;
+use bilibili_live_danmaku_cli::{ClientError, DepackedMessage, LiveClient, LiveMessage, LiveMessages, Protover};
+
+mod common;
+
+use common::{session, start_server, TEST_TIMEOUT};
+
+fn fixtures() -> Vec<Value> {
+    vec![
+        json!({ "cmd": "LIVE", "roomid": 4793604 }),
+        json!({ "cmd": "WARNING", "msg": "mock warning" }),
+        json!({
+            "cmd": "DANMU_MSG",
+            "info": [
+                [0, 1, 25, 16777215, 1700000000000u64, 0, 0, "", 0, 0, 0, "", 0, "{}", "{}",
+                    { "user": { "uid": 5, "base": { "name": "alice" }, "guard": null, "medal": null } }],
+                "hello",
+                [5, "alice", 0, 0],
+                [], [], [], 0, 0, {}, {}
+            ]
+        }),
+    ]
+}
+
+// Collect the first messages accepted by the filter, failing the test if they do not arrive in time
+fn first_messages(messages: LiveMessages, count: usize, filter: fn(&LiveMessage) -> bool) -> Vec<LiveMessage> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for message in messages {
+            let message = message.expect("connection failed");
+            if filter(&message) && sender.send(message).is_err() {
+                return;
+            }
+        }
+    });
+    (0..count).map(|_| receiver.recv_timeout(TEST_TIMEOUT).expect("message not received in time")).collect()
+}
+
+#[test]
+fn certificate_is_accepted() {
+    let url = start_server(MockServer::bind("127.0.0.1:0", vec![]).unwrap());
+    let mut client = LiveClient::connect(&url, session(), Protover::Brotli, None, None).unwrap();
+
+    // Reads time out every second, a few rounds are enough for a local server
+    for _ in 0..5 {
+        let messages = client.recv_messages().unwrap();
+        if let Some(DepackedMessage::CertificateResp(resp)) = messages.first() {
+            assert_eq!(resp.code, 0);
+            return;
+        }
+    }
+    panic!("certificate response not received");
+}
+
+#[test]
+fn rejected_certificate_is_auth_rejected() {
+    let url = start_server(MockServer::bind("127.0.0.1:0", fixtures()).unwrap().reject_certificate(-101));
+    let mut client = LiveClient::connect(&url, session(), Protover::Brotli, None, None).unwrap();
+
+    for _ in 0..5 {
+        match client.recv_messages() {
+            Ok(messages) => assert!(messages.is_empty(), "no messages expected before the certificate response"),
+            Err(ClientError::AuthRejected(code)) => {
+                assert_eq!(code, -101);
+                assert!(!client.certificate_accepted());
+                return;
+            }
+            Err(e) => panic!("unexpected error: {}", e)
+        }
+    }
+    panic!("certificate rejection not received");
+}
+
+#[test]
+fn fixtures_are_received_as_live_messages() {
+    let url = start_server(MockServer::bind("127.0.0.1:0", fixtures()).unwrap());
+    let client = LiveClient::connect(&url, session(), Protover::Brotli, None, None).unwrap();
+
+    let messages = first_messages(client.messages(TimeDelta::seconds(20), TimeDelta::seconds(60)), 3, |_| true);
+    assert!(matches!(&messages[0], LiveMessage::LiveStart(info) if info.room_id == 4793604));
+    assert!(matches!(&messages[1], LiveMessage::Warning(info) if info.message == "mock warning"));
+    assert!(matches!(&messages[2], LiveMessage::Danmaku(info) if info.text == "hello" && info.user.uid == 5));
+}
+
+#[test]
+fn popularity_comes_from_heartbeat_responses() {
+    let url = start_server(MockServer::bind("127.0.0.1:0", vec![]).unwrap());
+    let client = LiveClient::connect(&url, session(), Protover::Brotli, None, None).unwrap();
+
+    let messages = first_messages(
+        client.messages(TimeDelta::milliseconds(100), TimeDelta::seconds(5)),
+        2,
+        |message| matches!(message, LiveMessage::Popularity(_))
+    );
+    let counts: Vec<u64> = messages.iter().map(|message| match message {
+        LiveMessage::Popularity(info) => info.count,
+        _ => unreachable!()
+    }).collect();
+    assert_eq!(counts, [1, 2]);
+}
+
+#[test]
+fn client_reconnects_after_server_drops_connection() {
+    let url = start_server(MockServer::bind("127.0.0.1:0", fixtures()).unwrap().disconnect_after_fixtures());
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bilibili-live-danmaku-cli"))
+        .args(["--room-id", "4793604", "--server-url", &url, "--reconnect-delay", "50"])
+        .env("NO_COLOR", "1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                return;
+            }
+        }
+    });
+
+    // Fixtures are pushed once per connection, seeing them twice means the client came back
+    let mut warnings = 0;
+    while warnings < 2 {
+        match receiver.recv_timeout(TEST_TIMEOUT) {
+            Ok(line) if line.contains("mock warning") => warnings += 1,
+            Ok(_) => {}
+            Err(_) => break
+        }
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(warnings, 2, "client did not reconnect after the server dropped the connection");
+}
+
+#[test]
+fn retry_limit_applies_when_server_keeps_dropping_connections() {
+    let url = start_server(MockServer::bind("127.0.0.1:0", fixtures()).unwrap().disconnect_after_fixtures());
+
+    // Dropped connections never prove healthy, so backoff is not reset and the retry limit is reached
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bilibili-live-danmaku-cli"))
+        .args(["--room-id", "4793604", "--server-url", &url, "--reconnect-delay", "10", "--max-retries", "2"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + TEST_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            panic!("client kept reconnecting past the retry limit");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(!status.success());
+}
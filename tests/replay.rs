@@ -0,0 +1,65 @@
+use std::process::Command;
+
+use bincode::Options;
+use serde_json::json;
+
+use bilibili_live_danmaku_cli::capture::{
+    capture_bincode_options, CaptureFileHeader, CaptureRecordHeader, FrameDirection, CAPTURE_MAGIC, CAPTURE_VERSION
+};
+use bilibili_live_danmaku_cli::packet::encoder::PacketEncoder;
+use bilibili_live_danmaku_cli::packet::ws::Protocol;
+
+fn gift_frame(encoder: &mut PacketEncoder) -> Vec<u8> {
+    let gift = json!({
+        "cmd": "SEND_GIFT",
+        "data": {
+            "giftName": "小花花",
+            "num": 1,
+            "guard_level": 0,
+            "sender_uinfo": { "uid": 5, "base": { "name": "alice" }, "guard": null, "medal": null }
+        }
+    });
+    encoder.encode_commands(Protocol::CommandBrotli, &[serde_json::to_vec(&gift).unwrap()]).unwrap()
+}
+
+// Capture file with inbound frames received at the given times
+fn write_capture(path: &std::path::Path, frames: &[(i64, Vec<u8>)]) {
+    let header = CaptureFileHeader { magic: CAPTURE_MAGIC, version: CAPTURE_VERSION, reserved: 0 };
+    let mut content = capture_bincode_options().serialize(&header).unwrap();
+    for (timestamp_ms, data) in frames {
+        let record = CaptureRecordHeader {
+            timestamp_ms: *timestamp_ms,
+            direction: FrameDirection::Inbound as u8,
+            length: data.len() as u32,
+        };
+        content.extend(capture_bincode_options().serialize(&record).unwrap());
+        content.extend(data);
+    }
+    std::fs::write(path, content).unwrap();
+}
+
+#[test]
+fn gift_combos_follow_capture_time_at_any_speed() {
+    let path = std::env::temp_dir().join(format!("bilibili-live-danmaku-cli-test-{}-combo.cap", std::process::id()));
+    let mut encoder = PacketEncoder::new();
+    // Two gifts within the combo interval, then one sent long after
+    let start = 1700000000000;
+    write_capture(&path, &[
+        (start, gift_frame(&mut encoder)),
+        (start + 500, gift_frame(&mut encoder)),
+        (start + 5000, gift_frame(&mut encoder)),
+    ]);
+
+    // Replaying as fast as possible takes far less than the combo interval of wall clock time
+    let output = Command::new(env!("CARGO_BIN_EXE_bilibili-live-danmaku-cli"))
+        .args(["--replay", path.to_str().unwrap(), "--replay-speed", "0", "--gift-combo", "--combo-interval", "2000"])
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let gifts: Vec<&str> = stdout.lines().filter(|line| line.contains("投餵了")).collect();
+    assert_eq!(gifts, [" * alice 投餵了 2 個 小花花", " * alice 投餵了 1 個 小花花"]);
+}